@@ -0,0 +1,17 @@
+#[tokio::main]
+pub async fn main() {
+    let _ = pretty_env_logger::try_init();
+
+    let browser = simple_mdns::Browser::new("_raop._tcp").unwrap();
+    let mut events = browser.browse().await.unwrap();
+
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => println!("{:?}", event),
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+}
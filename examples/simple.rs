@@ -3,6 +3,13 @@ pub async fn main() {
     let _ = pretty_env_logger::try_init();
 
     let service = simple_mdns::Service::new("_raop._tcp", "test", 1234, vec!["testtest"]);
-    let server = simple_mdns::Server::new(vec![service]).unwrap();
-    server.serve().await.unwrap();
+    let server = simple_mdns::Server::builder()
+        .service(service)
+        .on_name(|event| println!("{:?}", event))
+        .build()
+        .unwrap();
+    let handle = server.serve().await.unwrap();
+
+    tokio::signal::ctrl_c().await.unwrap();
+    handle.shutdown().await.unwrap();
 }
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_mdns::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::parse(data) {
+        // anything we can parse must be written back as a packet that parses to the same bytes again
+        let raw = packet.write();
+        let packet2 = Packet::parse(&raw).expect("failed to parse written packet");
+
+        assert_eq!(raw, packet2.write());
+    }
+});
@@ -0,0 +1,196 @@
+mod querier;
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use log::{debug, error, trace};
+use tokio::{
+    sync::mpsc,
+    task,
+    time::{self, Instant},
+};
+
+use super::{
+    cache::Cache,
+    error::{Error, Result},
+    multicast::{InterfaceType, Message, Sockets, MULTICAST_ADDR_V4, MULTICAST_ADDR_V6},
+    packet::{Packet, ResourceRecord},
+};
+use querier::Querier;
+
+/// Resolved service instance
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServiceInfo {
+    /// Full instance name, e.g. `test._raop._tcp.local`
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub txt: Vec<String>,
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BrowseEvent {
+    /// Instance has been resolved, or its records have changed
+    ServiceFound(ServiceInfo),
+    /// Instance with the given name has gone away
+    ServiceRemoved(String),
+}
+
+pub struct Browser {
+    service_type: String,
+    interfaces: Vec<if_addrs::Interface>,
+}
+
+impl Browser {
+    /// Browser for services of a type like `_raop._tcp`, on all interfaces
+    pub fn new(service_type: &str) -> Result<Self> {
+        let interfaces = if_addrs::get_if_addrs()?;
+        if multicast_interfaces(&interfaces).is_empty() {
+            return Err(Error::NoInterface);
+        }
+
+        Ok(Self {
+            service_type: format!("{}.local", service_type),
+            interfaces,
+        })
+    }
+
+    /// Starts querying in background, until the returned handle is dropped
+    pub async fn browse(&self) -> Result<BrowserHandle> {
+        let sockets = Sockets::open(&self.interfaces).await?;
+        let cache = Arc::new(Mutex::new(Cache::new()));
+        let querier = Querier::new(self.service_type.clone(), cache.clone(), Instant::now());
+
+        let (sender, events) = mpsc::channel(32);
+        task::spawn(Self::browse_loop(sockets, multicast_interfaces(&self.interfaces), querier, sender));
+
+        Ok(BrowserHandle { events, cache })
+    }
+
+    async fn browse_loop(sockets: Sockets, interfaces: Vec<(SocketAddr, InterfaceType)>, mut querier: Querier, events: mpsc::Sender<BrowseEvent>) {
+        let (sender, mut receiver) = mpsc::channel(32);
+        let readers = sockets.spawn_readers(sender);
+
+        if let Err(err) = Self::run(&sockets, &interfaces, &mut receiver, &events, &mut querier).await {
+            error!("Browser stopped: {}", err);
+        }
+
+        for reader in &readers {
+            reader.abort();
+        }
+
+        for socket in sockets.iter() {
+            if let Err(err) = socket.leave() {
+                debug!("failed to leave multicast group: {}", err);
+            }
+        }
+
+        for reader in readers {
+            let _ = reader.await;
+        }
+    }
+
+    // returns when the handle is dropped
+    async fn run(
+        sockets: &Sockets,
+        interfaces: &[(SocketAddr, InterfaceType)],
+        receiver: &mut mpsc::Receiver<io::Result<Message>>,
+        events: &mpsc::Sender<BrowseEvent>,
+        querier: &mut Querier,
+    ) -> Result<()> {
+        loop {
+            let deadline = querier.next_deadline();
+
+            tokio::select! {
+                _ = events.closed() => return Ok(()),
+                message = receiver.recv() => {
+                    let message = match message {
+                        Some(message) => message?,
+                        None => return Ok(()),
+                    };
+                    trace!("receive from {}, raw {:?}", message.sender, message.data);
+
+                    for event in querier.handle_message(&message, Instant::now()) {
+                        if events.send(event).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    let (found, queries) = querier.handle_timer(Instant::now());
+
+                    for query in queries {
+                        Self::send(sockets, interfaces, &query).await;
+                    }
+
+                    for event in found {
+                        if events.send(event).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(sockets: &Sockets, interfaces: &[(SocketAddr, InterfaceType)], query: &Packet) {
+        let data = query.write();
+
+        for (group, interface) in interfaces {
+            trace!("sending query to {} on interface {}, raw {:?}", group, interface, data);
+
+            if let Some(socket) = sockets.get(group) {
+                // interfaces may go away at any time
+                if let Err(err) = socket.write(&data, *interface).await {
+                    debug!("failed to send: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Handle to a running browser, which receives its events. Dropping it stops the browser.
+pub struct BrowserHandle {
+    events: mpsc::Receiver<BrowseEvent>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl BrowserHandle {
+    /// Waits for the next event, returns `None` if the browser has stopped on error
+    pub async fn next(&mut self) -> Option<BrowseEvent> {
+        self.events.recv().await
+    }
+
+    /// Records of any name received so far and not yet expired, with TTLs of the remaining time.
+    /// The cache is limited in size, records closest to expiry are dropped first when it is full.
+    pub fn cached_records(&self) -> Vec<ResourceRecord> {
+        self.cache.lock().unwrap().records(Instant::now())
+    }
+}
+
+// multicast group and interface pairs to send queries to
+fn multicast_interfaces(interfaces: &[if_addrs::Interface]) -> Vec<(SocketAddr, InterfaceType)> {
+    let mut result = Vec::new();
+
+    for interface in interfaces.iter().filter(|x| !x.is_loopback()) {
+        let Some(index) = interface.index else {
+            continue;
+        };
+
+        let group = if interface.ip().is_ipv4() {
+            MULTICAST_ADDR_V4
+        } else {
+            MULTICAST_ADDR_V6
+        };
+        let item = (group, index as InterfaceType);
+        if !result.contains(&item) {
+            result.push(item);
+        }
+    }
+
+    result
+}
@@ -0,0 +1,590 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::debug;
+use rand::Rng;
+use tokio::time::Instant;
+
+use super::{BrowseEvent, ServiceInfo};
+use crate::{
+    cache::Cache,
+    multicast::{Message, MULTICAST_ADDR_V4},
+    packet::{HeaderFlags, Packet, Question, ResourceRecord, ResourceRecordData, ResourceType},
+};
+
+// continuous querying intervals (rfc6762 5.2)
+const FIRST_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_QUERY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// 1500 byte ethernet mtu without ipv6 and udp headers (rfc6762 17)
+const MAX_QUERY_SIZE: usize = 1452;
+// queries for missing records before giving up, until the instance is announced again
+const RESOLVE_ATTEMPTS: u32 = 4;
+
+struct Instance {
+    name: String,
+    reported: Option<ServiceInfo>,
+    resolve: Option<Resolve>,
+}
+
+// questions for records missing to report an instance, repeated with doubling intervals
+struct Resolve {
+    questions: Vec<Question>,
+    next: Option<Instant>,
+    interval: Duration,
+    attempts: u32,
+}
+
+impl Resolve {
+    fn new(questions: Vec<Question>, now: Instant) -> Self {
+        Self {
+            questions,
+            next: Some(now),
+            interval: FIRST_QUERY_INTERVAL,
+            attempts: 0,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        matches!(self.next, Some(x) if x <= now)
+    }
+
+    fn sent(&mut self, now: Instant) {
+        self.attempts += 1;
+        self.next = (self.attempts < RESOLVE_ATTEMPTS).then_some(now + self.interval);
+        self.interval *= 2;
+    }
+}
+
+// names are case insensitive (rfc6762 16)
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+// records of the browsed type, its instances and their hosts are kept fresh
+fn is_wanted(service_type: &str, instances: &HashMap<String, Instance>, record: &ResourceRecord) -> bool {
+    let name = key(&record.name().to_string());
+
+    match record.r#type() {
+        ResourceType::PTR => record.name().equals(service_type),
+        ResourceType::SRV | ResourceType::TXT => instances.contains_key(&name),
+        ResourceType::A | ResourceType::AAAA => instances.values().any(|x| x.reported.as_ref().is_some_and(|x| key(&x.host) == name)),
+        _ => false,
+    }
+}
+
+pub(super) struct Querier {
+    service_type: String,
+    cache: Arc<Mutex<Cache>>,
+    instances: HashMap<String, Instance>,
+    next_browse: Instant,
+    browse_interval: Duration,
+}
+
+impl Querier {
+    pub fn new(service_type: String, cache: Arc<Mutex<Cache>>, now: Instant) -> Self {
+        Self {
+            service_type,
+            cache,
+            instances: HashMap::new(),
+            // random delay before first query (rfc6762 5.2)
+            next_browse: now + Duration::from_millis(rand::thread_rng().gen_range(20..=120)),
+            browse_interval: FIRST_QUERY_INTERVAL,
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let cache = self.cache.lock().unwrap();
+        let expiry = cache.next_expiry();
+        let refresh = cache.next_refresh(|x| is_wanted(&self.service_type, &self.instances, x));
+        let resolve = self.instances.values().filter_map(|x| x.resolve.as_ref()?.next).min();
+
+        [Some(self.next_browse), resolve, expiry, refresh].into_iter().flatten().min()
+    }
+
+    // instances gone with expired records, and due questions in a query with known answers
+    pub fn handle_timer(&mut self, now: Instant) -> (Vec<BrowseEvent>, Vec<Packet>) {
+        self.cache.lock().unwrap().remove_expired(now);
+        let events = self.update(now);
+
+        let mut questions = Vec::new();
+
+        // doubling intervals between browse queries
+        if self.next_browse <= now {
+            questions.push(Question::new(&self.service_type, ResourceType::PTR, false));
+
+            self.next_browse = now + self.browse_interval;
+            self.browse_interval = (self.browse_interval * 2).min(MAX_QUERY_INTERVAL);
+        }
+
+        for resolve in self.instances.values_mut().filter_map(|x| x.resolve.as_mut()) {
+            if !resolve.is_due(now) {
+                continue;
+            }
+
+            resolve.sent(now);
+            for question in &resolve.questions {
+                if !questions.contains(question) {
+                    questions.push(question.clone());
+                }
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        for record in cache.take_refreshes(now, |x| is_wanted(&self.service_type, &self.instances, x)) {
+            let question = Question::new(&record.name().to_string(), record.r#type(), false);
+            if !questions.contains(&question) {
+                questions.push(question);
+            }
+        }
+
+        if questions.is_empty() {
+            return (events, Vec::new());
+        }
+
+        let answers = questions.iter().flat_map(|x| cache.known_answers(x, now)).collect();
+
+        (events, Self::split_known_answers(questions, answers))
+    }
+
+    // known answers that don't fit continue in packets without questions, all but the last truncated (rfc6762 7.2)
+    fn split_known_answers(questions: Vec<Question>, answers: Vec<ResourceRecord>) -> Vec<Packet> {
+        let mut result = Vec::new();
+        let mut packet = Packet::new_query(0, questions, Vec::new(), Vec::new());
+
+        for answer in answers {
+            packet.answers.push(answer);
+
+            // a single answer is sent even if too large
+            if packet.write().len() > MAX_QUERY_SIZE && (packet.answers.len() > 1 || !packet.questions.is_empty()) {
+                let answer = packet.answers.pop().unwrap();
+                packet.header = packet.header.with_flags(HeaderFlags::TRUNCATED, true);
+
+                result.push(packet);
+                packet = Packet::new_query(0, Vec::new(), vec![answer], Vec::new());
+            }
+        }
+        result.push(packet);
+
+        result
+    }
+
+    pub fn handle_message(&mut self, message: &Message, now: Instant) -> Vec<BrowseEvent> {
+        let packet = match Packet::parse(&message.data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("Invalid packet from {}: {}", message.sender, err);
+
+                return Vec::new();
+            }
+        };
+
+        if !packet.header.is_response() {
+            return Vec::new();
+        }
+
+        // rfc6762 6
+        if message.sender.port() != MULTICAST_ADDR_V4.port() {
+            debug!(
+                "Ignoring response from {} with source port other than {}",
+                message.sender,
+                MULTICAST_ADDR_V4.port()
+            );
+
+            return Vec::new();
+        }
+
+        // instances announced again are resolved again, once given up
+        for record in packet.answers.iter().filter(|x| x.name().equals(&self.service_type) && x.ttl() > 0) {
+            let ResourceRecordData::PTR(target) = record.data() else {
+                continue;
+            };
+
+            if let Some(instance) = self.instances.get_mut(&key(&target.to_string())) {
+                if instance.resolve.as_ref().is_some_and(|x| x.next.is_none()) {
+                    instance.resolve = None;
+                }
+            }
+        }
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for record in packet.answers.into_iter().chain(packet.nameservers).chain(packet.additionals) {
+                cache.insert(record, now);
+            }
+        }
+
+        self.update(now)
+    }
+
+    // reports instances whose records have changed, and asks for missing ones
+    fn update(&mut self, now: Instant) -> Vec<BrowseEvent> {
+        let cache = self.cache.lock().unwrap();
+        let mut events = Vec::new();
+
+        let pointers = cache
+            .get(&self.service_type, ResourceType::PTR, now)
+            .into_iter()
+            .filter_map(|x| match x.data() {
+                ResourceRecordData::PTR(target) => Some(target.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        self.instances.retain(|key, instance| {
+            let remaining = pointers.iter().any(|x| x.to_ascii_lowercase() == *key);
+            if !remaining && instance.reported.is_some() {
+                events.push(BrowseEvent::ServiceRemoved(instance.name.clone()));
+            }
+
+            remaining
+        });
+
+        for name in pointers {
+            let instance = self.instances.entry(key(&name)).or_insert_with(|| Instance {
+                name,
+                reported: None,
+                resolve: None,
+            });
+
+            let srv = cache
+                .get(&instance.name, ResourceType::SRV, now)
+                .into_iter()
+                .find_map(|x| match x.data() {
+                    ResourceRecordData::SRV { port, target, .. } => Some((*port, target.to_string())),
+                    _ => None,
+                });
+
+            let info = srv.as_ref().and_then(|(port, host)| {
+                let addresses = [ResourceType::A, ResourceType::AAAA]
+                    .into_iter()
+                    .flat_map(|x| cache.get(host, x, now))
+                    .filter_map(|x| match x.data() {
+                        ResourceRecordData::A(ip) => Some(IpAddr::V4(*ip)),
+                        ResourceRecordData::AAAA(ip) => Some(IpAddr::V6(*ip)),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let txt = cache
+                    .get(&instance.name, ResourceType::TXT, now)
+                    .into_iter()
+                    .find_map(|x| match x.data() {
+                        ResourceRecordData::TXT(txt) => Some(txt.clone()),
+                        _ => None,
+                    });
+
+                (!addresses.is_empty()).then(|| ServiceInfo {
+                    name: instance.name.clone(),
+                    host: host.clone(),
+                    port: *port,
+                    txt: txt.unwrap_or_default(),
+                    addresses,
+                })
+            });
+
+            if info != instance.reported {
+                instance.reported = info.clone();
+
+                events.push(match info {
+                    Some(info) => BrowseEvent::ServiceFound(info),
+                    None => BrowseEvent::ServiceRemoved(instance.name.clone()),
+                });
+            }
+
+            let questions = match &srv {
+                None => vec![
+                    Question::new(&instance.name, ResourceType::SRV, false),
+                    Question::new(&instance.name, ResourceType::TXT, false),
+                ],
+                Some((_, host)) if instance.reported.is_none() => {
+                    vec![
+                        Question::new(host, ResourceType::A, false),
+                        Question::new(host, ResourceType::AAAA, false),
+                    ]
+                }
+                _ => Vec::new(),
+            };
+
+            if questions.is_empty() {
+                instance.resolve = None;
+            } else if !matches!(&instance.resolve, Some(x) if x.questions == questions) {
+                instance.resolve = Some(Resolve::new(questions, now));
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::packet::{Name, ResourceRecord};
+
+    fn message(answers: Vec<ResourceRecord>, additionals: Vec<ResourceRecord>) -> Message {
+        Message {
+            data: Packet::new_response(0, Vec::new(), answers, Vec::new(), additionals).write(),
+            sender: SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 5353).into(),
+            interface: 2,
+        }
+    }
+
+    fn ptr(ttl: u32) -> ResourceRecord {
+        ResourceRecord::new("_raop._tcp.local", ttl, ResourceRecordData::PTR(Name::new("test._raop._tcp.local")))
+    }
+
+    fn srv() -> ResourceRecord {
+        ResourceRecord::new(
+            "test._raop._tcp.local",
+            120,
+            ResourceRecordData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 1234,
+                target: Name::new("host.local"),
+            },
+        )
+    }
+
+    fn txt() -> ResourceRecord {
+        ResourceRecord::new("test._raop._tcp.local", 4500, ResourceRecordData::TXT(vec!["a=b".into()]))
+    }
+
+    fn a() -> ResourceRecord {
+        ResourceRecord::new("host.local", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 2)))
+    }
+
+    fn querier(now: Instant) -> Querier {
+        Querier::new("_raop._tcp.local".into(), Arc::new(Mutex::new(Cache::new())), now)
+    }
+
+    // with browse queries out of the way
+    fn quiet_querier(now: Instant) -> Querier {
+        let mut querier = querier(now);
+        querier.next_browse = now + MAX_QUERY_INTERVAL;
+
+        querier
+    }
+
+    // SRV and A records received at the time are refreshed at 80-82% of their ttl
+    fn assert_refresh_deadline(querier: &Querier, received: Instant) {
+        let deadline = querier.next_deadline().unwrap();
+        assert!(deadline >= received + Duration::from_secs(96) && deadline <= received + Duration::from_millis(98400));
+    }
+
+    // query sent at the time, expected to fit in a single packet
+    fn single_query(querier: &mut Querier, now: Instant) -> Option<Packet> {
+        let mut queries = querier.handle_timer(now).1;
+        assert!(queries.len() <= 1);
+
+        queries.pop()
+    }
+
+    fn questions(packet: &Packet) -> Vec<(String, ResourceType)> {
+        packet.questions.iter().map(|x| (x.name.to_string(), x.r#type)).collect()
+    }
+
+    #[test]
+    fn continuous_query() {
+        let now = Instant::now();
+        let mut querier = querier(now);
+
+        let mut time = querier.next_deadline().unwrap();
+        assert!(time >= now + Duration::from_millis(20) && time <= now + Duration::from_millis(120));
+
+        let query = single_query(&mut querier, time).unwrap();
+        assert!(query.header.is_query());
+        assert_eq!(questions(&query), vec![("_raop._tcp.local".into(), ResourceType::PTR)]);
+        assert!(!query.questions[0].unicast);
+        assert!(query.answers.is_empty());
+        assert!(single_query(&mut querier, time).is_none());
+
+        let mut interval = FIRST_QUERY_INTERVAL;
+        for _ in 0..16 {
+            assert!(single_query(&mut querier, time + interval - Duration::from_millis(1)).is_none());
+            assert_eq!(querier.next_deadline(), Some(time + interval));
+
+            time += interval;
+            assert!(single_query(&mut querier, time).is_some());
+            interval = (interval * 2).min(MAX_QUERY_INTERVAL);
+        }
+        assert_eq!(interval, MAX_QUERY_INTERVAL);
+    }
+
+    #[test]
+    fn refresh_with_known_answers() {
+        let now = Instant::now();
+        let mut querier = querier(now);
+        querier.handle_message(&message(vec![ptr(4500)], vec![srv(), txt(), a()]), now);
+
+        let time = querier.next_deadline().unwrap();
+        let query = single_query(&mut querier, time).unwrap();
+        assert_eq!(questions(&query), vec![("_raop._tcp.local".into(), ResourceType::PTR)]);
+        assert_eq!(query.answers.len(), 1);
+        assert!(matches!(query.answers[0].data(), ResourceRecordData::PTR(x) if x.equals("test._raop._tcp.local")));
+
+        // SRV and A at 80% of their ttl, without themselves as known answers
+        let query = single_query(&mut querier, now + Duration::from_secs(99)).unwrap();
+        assert_eq!(
+            questions(&query),
+            vec![
+                ("_raop._tcp.local".into(), ResourceType::PTR),
+                ("host.local".into(), ResourceType::A),
+                ("test._raop._tcp.local".into(), ResourceType::SRV)
+            ]
+        );
+        assert_eq!(query.answers.iter().map(|x| x.r#type()).collect::<Vec<_>>(), vec![ResourceType::PTR]);
+    }
+
+    #[test]
+    fn known_answers_without_cache_flush() {
+        let now = Instant::now();
+        let mut querier = querier(now);
+        querier.handle_message(&message(vec![ptr(4500).with_cache_flush(true)], vec![srv(), txt(), a()]), now);
+
+        let time = querier.next_deadline().unwrap();
+        let query = Packet::parse(&single_query(&mut querier, time).unwrap().write()).unwrap();
+        assert_eq!(questions(&query), vec![("_raop._tcp.local".into(), ResourceType::PTR)]);
+        assert_eq!(query.answers.len(), 1);
+        assert!(!query.answers[0].cache_flush());
+    }
+
+    #[test]
+    fn split_known_answers() {
+        let question = Question::new("_raop._tcp.local", ResourceType::PTR, false);
+        let answers = (0..100)
+            .map(|x| {
+                let target = format!("instance with a long name {}._raop._tcp.local", x);
+                ResourceRecord::new("_raop._tcp.local", 4500, ResourceRecordData::PTR(Name::new(&target)))
+            })
+            .collect::<Vec<_>>();
+
+        let queries = Querier::split_known_answers(vec![question.clone()], answers.clone());
+        assert!(queries.len() > 1);
+        assert_eq!(queries[0].questions, vec![question]);
+        assert!(queries[1..].iter().all(|x| x.questions.is_empty()));
+        assert!(queries.iter().all(|x| x.write().len() <= MAX_QUERY_SIZE));
+
+        // all but the last are truncated
+        let (last, others) = queries.split_last().unwrap();
+        assert!(others.iter().all(|x| x.header.is_truncated()));
+        assert!(!last.header.is_truncated());
+        assert_eq!(queries.into_iter().flat_map(|x| x.answers).collect::<Vec<_>>(), answers);
+    }
+
+    #[test]
+    fn service_found_and_removed() {
+        let now = Instant::now();
+        let mut querier = quiet_querier(now);
+
+        let events = querier.handle_message(&message(vec![ptr(4500)], vec![srv(), txt(), a()]), now);
+        assert_eq!(
+            events,
+            vec![BrowseEvent::ServiceFound(ServiceInfo {
+                name: "test._raop._tcp.local".into(),
+                host: "host.local".into(),
+                port: 1234,
+                txt: vec!["a=b".into()],
+                addresses: vec![Ipv4Addr::new(192, 168, 1, 2).into()],
+            })]
+        );
+        // no resolving query, only refresh of the records
+        assert!(single_query(&mut querier, now).is_none());
+        assert_refresh_deadline(&querier, now);
+
+        // unchanged records are not reported again
+        assert!(querier.handle_message(&message(vec![ptr(4500)], vec![srv(), txt(), a()]), now).is_empty());
+
+        // removed after a second of goodbye
+        assert!(querier.handle_message(&message(vec![ptr(0)], Vec::new()), now).is_empty());
+        assert_eq!(querier.next_deadline(), Some(now + Duration::from_secs(1)));
+        assert!(querier.handle_timer(now + Duration::from_millis(999)).0.is_empty());
+
+        let (events, queries) = querier.handle_timer(now + Duration::from_secs(1));
+        assert_eq!(events, vec![BrowseEvent::ServiceRemoved("test._raop._tcp.local".into())]);
+        assert!(queries.is_empty());
+    }
+
+    #[test]
+    fn service_expired() {
+        let now = Instant::now();
+        let mut querier = quiet_querier(now);
+
+        assert_eq!(querier.handle_message(&message(vec![ptr(4500)], vec![srv(), txt(), a()]), now).len(), 1);
+        assert_refresh_deadline(&querier, now);
+
+        let (events, _) = querier.handle_timer(now + Duration::from_secs(120));
+        assert_eq!(events, vec![BrowseEvent::ServiceRemoved("test._raop._tcp.local".into())]);
+    }
+
+    #[test]
+    fn resolve_missing_records() {
+        let now = Instant::now();
+        let mut querier = querier(now);
+
+        assert!(querier.handle_message(&message(vec![ptr(4500)], Vec::new()), now).is_empty());
+        let query = single_query(&mut querier, now).unwrap();
+        assert_eq!(
+            questions(&query),
+            vec![
+                ("test._raop._tcp.local".into(), ResourceType::SRV),
+                ("test._raop._tcp.local".into(), ResourceType::TXT)
+            ]
+        );
+
+        assert!(querier.handle_message(&message(vec![srv(), txt()], Vec::new()), now).is_empty());
+        let query = single_query(&mut querier, now).unwrap();
+        assert_eq!(
+            questions(&query),
+            vec![("host.local".into(), ResourceType::A), ("host.local".into(), ResourceType::AAAA)]
+        );
+
+        let events = querier.handle_message(&message(vec![a()], Vec::new()), now);
+        assert!(matches!(&events[..], [BrowseEvent::ServiceFound(info)] if info.port == 1234));
+    }
+
+    #[test]
+    fn resolve_retries() {
+        let now = Instant::now();
+        let mut querier = querier(now);
+        querier.handle_message(&message(vec![ptr(4500)], Vec::new()), now);
+
+        let asks_srv = |query: Option<Packet>| query.is_some_and(|x| questions(&x).contains(&("test._raop._tcp.local".into(), ResourceType::SRV)));
+
+        // doubling intervals until given up
+        let mut time = now;
+        for interval in [1, 2, 4] {
+            assert!(asks_srv(single_query(&mut querier, time)));
+
+            let next = time + Duration::from_secs(interval);
+            assert!(!asks_srv(single_query(&mut querier, next - Duration::from_millis(1))));
+            time = next;
+        }
+        assert!(asks_srv(single_query(&mut querier, time)));
+        assert!(!asks_srv(single_query(&mut querier, time + Duration::from_secs(60))));
+
+        // until announced again
+        time += Duration::from_secs(60);
+        querier.handle_message(&message(vec![ptr(4500)], Vec::new()), time);
+        assert!(asks_srv(single_query(&mut querier, time)));
+    }
+
+    #[test]
+    fn ignore_other_source_port() {
+        let now = Instant::now();
+        let mut querier = querier(now);
+
+        let mut message = message(vec![ptr(4500)], vec![srv(), txt(), a()]);
+        message.sender.set_port(1234);
+        assert!(querier.handle_message(&message, now).is_empty());
+        assert!(querier.cache.lock().unwrap().records(now).is_empty());
+    }
+}
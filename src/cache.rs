@@ -0,0 +1,340 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::packet::{Question, ResourceRecord, ResourceType};
+
+// grace period for goodbye and cache flush (rfc6762 10.1, 10.2)
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+// percentages of ttl to query for a record in use at (rfc6762 5.2)
+const REFRESH_AT: [u64; 4] = [80, 85, 90, 95];
+// records kept at most, the ones closest to expiry are evicted first
+const MAX_RECORDS: usize = 4096;
+
+// lowercase name and type, as names are case insensitive (rfc6762 16)
+type Key = (String, u16);
+
+fn key(name: &str, r#type: ResourceType) -> Key {
+    (name.to_ascii_lowercase(), r#type.into())
+}
+
+struct Entry {
+    record: ResourceRecord,
+    received: Instant,
+    expires: Instant,
+    refreshes: usize,
+    refresh: Option<Instant>,
+}
+
+impl Entry {
+    fn new(record: ResourceRecord, now: Instant) -> Self {
+        let mut entry = Self {
+            expires: now + Duration::from_secs(record.ttl() as u64),
+            record,
+            received: now,
+            refreshes: 0,
+            refresh: None,
+        };
+        entry.refresh = entry.refresh_time();
+
+        entry
+    }
+
+    // with 2% random variation
+    fn refresh_time(&self) -> Option<Instant> {
+        let percent = REFRESH_AT.get(self.refreshes)? + rand::thread_rng().gen_range(0..=2);
+
+        Some(self.received + Duration::from_millis(self.record.ttl() as u64 * 10 * percent))
+    }
+}
+
+/// Records learned from responses, until their TTL has passed
+#[derive(Default)]
+pub(crate) struct Cache {
+    entries: BTreeMap<Key, Vec<Entry>>,
+    len: usize,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, record: ResourceRecord, now: Instant) {
+        let key = key(&record.name().to_string(), record.r#type());
+        let same_class = |x: &ResourceRecord| x.class() == record.class();
+
+        // goodbye only shortens life of a known record
+        if record.ttl() == 0 {
+            for entry in self
+                .entries
+                .get_mut(&key)
+                .into_iter()
+                .flatten()
+                .filter(|x| same_class(&x.record) && x.record.data() == record.data())
+            {
+                entry.expires = entry.expires.min(now + FLUSH_DELAY);
+                entry.refresh = None;
+            }
+
+            return;
+        }
+
+        let exists = self
+            .entries
+            .get(&key)
+            .is_some_and(|x| x.iter().any(|x| same_class(&x.record) && x.record.data() == record.data()));
+        if !exists && self.len >= MAX_RECORDS {
+            self.evict();
+        }
+
+        let set = self.entries.entry(key).or_default();
+
+        // older members of a unique record set are replaced by the new one
+        if record.cache_flush() {
+            for entry in set.iter_mut().filter(|x| same_class(&x.record) && x.received + FLUSH_DELAY <= now) {
+                entry.expires = entry.expires.min(now + FLUSH_DELAY);
+                entry.refresh = None;
+            }
+        }
+
+        match set.iter_mut().find(|x| same_class(&x.record) && x.record.data() == record.data()) {
+            Some(entry) => *entry = Entry::new(record, now),
+            None => {
+                set.push(Entry::new(record, now));
+                self.len += 1;
+            }
+        }
+    }
+
+    // removes the record closest to expiry
+    fn evict(&mut self) {
+        let Some((key, index)) = self
+            .entries
+            .iter()
+            .flat_map(|(key, set)| set.iter().enumerate().map(move |(index, x)| (x.expires, key, index)))
+            .min_by_key(|(expires, ..)| *expires)
+            .map(|(_, key, index)| (key.clone(), index))
+        else {
+            return;
+        };
+
+        if let Some(set) = self.entries.get_mut(&key) {
+            set.remove(index);
+            self.len -= 1;
+
+            if set.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn next_refresh<F>(&self, wanted: F) -> Option<Instant>
+    where
+        F: Fn(&ResourceRecord) -> bool,
+    {
+        self.entries
+            .values()
+            .flatten()
+            .filter(|x| wanted(&x.record))
+            .filter_map(|x| x.refresh)
+            .min()
+    }
+
+    // wanted records to query for again now, until an answer refreshes them
+    pub fn take_refreshes<F>(&mut self, now: Instant, wanted: F) -> Vec<ResourceRecord>
+    where
+        F: Fn(&ResourceRecord) -> bool,
+    {
+        let mut result = Vec::new();
+
+        for entry in self.entries.values_mut().flatten().filter(|x| wanted(&x.record)) {
+            if !matches!(entry.refresh, Some(x) if x <= now) {
+                continue;
+            }
+
+            entry.refreshes += 1;
+            entry.refresh = entry.refresh_time();
+            result.push(entry.record.clone());
+        }
+
+        result
+    }
+
+    // answers the querier has with at least half of their ttl remaining (rfc6762 7.1),
+    // without cache flush bit which is only set in responses (rfc6762 10.2)
+    pub fn known_answers(&self, question: &Question, now: Instant) -> Vec<ResourceRecord> {
+        self.entries
+            .get(&key(&question.name.to_string(), question.r#type))
+            .into_iter()
+            .flatten()
+            .filter_map(|x| {
+                let record = Self::remaining(x, now)?.with_cache_flush(false);
+
+                (record.ttl() as u64 * 2 >= x.record.ttl() as u64).then_some(record)
+            })
+            .collect()
+    }
+
+    pub fn remove_expired(&mut self, now: Instant) {
+        for set in self.entries.values_mut() {
+            set.retain(|x| x.expires > now);
+        }
+        self.entries.retain(|_, set| !set.is_empty());
+        self.len = self.entries.values().map(Vec::len).sum();
+    }
+
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.entries.values().flatten().map(|x| x.expires).min()
+    }
+
+    /// Cached records, with TTLs of the remaining time
+    pub fn records(&self, now: Instant) -> Vec<ResourceRecord> {
+        self.entries.values().flatten().filter_map(|x| Self::remaining(x, now)).collect()
+    }
+
+    pub fn get(&self, name: &str, r#type: ResourceType, now: Instant) -> Vec<ResourceRecord> {
+        self.entries
+            .get(&key(name, r#type))
+            .into_iter()
+            .flatten()
+            .filter_map(|x| Self::remaining(x, now))
+            .collect()
+    }
+
+    fn remaining(entry: &Entry, now: Instant) -> Option<ResourceRecord> {
+        let remaining = entry.expires.checked_duration_since(now).filter(|x| !x.is_zero())?;
+
+        Some(entry.record.clone().with_ttl(remaining.as_secs() as u32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::packet::ResourceRecordData;
+
+    fn a(ip: u8, ttl: u32) -> ResourceRecord {
+        ResourceRecord::new("host.local", ttl, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, ip)))
+    }
+
+    #[test]
+    fn expire_by_ttl() {
+        let now = Instant::now();
+        let mut cache = Cache::new();
+
+        cache.insert(a(1, 120), now);
+        cache.insert(a(2, 60), now);
+        assert_eq!(cache.next_expiry(), Some(now + Duration::from_secs(60)));
+
+        let records = cache.get("HOST.local", ResourceType::A, now + Duration::from_secs(30));
+        assert_eq!(records.iter().map(|x| x.ttl()).collect::<Vec<_>>(), vec![90, 30]);
+
+        cache.remove_expired(now + Duration::from_secs(60));
+        assert_eq!(cache.records(now + Duration::from_secs(60)), vec![a(1, 60)]);
+
+        // refreshed by a new copy
+        cache.insert(a(1, 120), now + Duration::from_secs(60));
+        assert_eq!(cache.next_expiry(), Some(now + Duration::from_secs(180)));
+    }
+
+    #[test]
+    fn goodbye() {
+        let now = Instant::now();
+        let mut cache = Cache::new();
+
+        cache.insert(a(1, 120), now);
+        cache.insert(a(1, 0), now + Duration::from_secs(10));
+        assert_eq!(cache.next_expiry(), Some(now + Duration::from_secs(11)));
+
+        cache.remove_expired(now + Duration::from_secs(11));
+        assert!(cache.records(now + Duration::from_secs(11)).is_empty());
+
+        // unknown records are not cached from goodbye
+        cache.insert(a(2, 0), now);
+        assert!(cache.next_expiry().is_none());
+    }
+
+    #[test]
+    fn cache_flush() {
+        let now = Instant::now();
+        let mut cache = Cache::new();
+
+        cache.insert(a(1, 120), now);
+
+        // members received within the last second are kept
+        let later = now + Duration::from_millis(500);
+        cache.insert(a(2, 120).with_cache_flush(true), later);
+        assert_eq!(cache.next_expiry(), Some(now + Duration::from_secs(120)));
+
+        let later = now + Duration::from_secs(2);
+        cache.insert(a(3, 120).with_cache_flush(true), later);
+        assert_eq!(cache.next_expiry(), Some(later + FLUSH_DELAY));
+
+        cache.remove_expired(later + FLUSH_DELAY);
+        assert_eq!(cache.get("host.local", ResourceType::A, later).len(), 1);
+    }
+
+    #[test]
+    fn refresh() {
+        let now = Instant::now();
+        let mut cache = Cache::new();
+        cache.insert(a(1, 100), now);
+
+        for percent in REFRESH_AT {
+            let refresh = cache.next_refresh(|_| true).unwrap();
+            assert!(refresh >= now + Duration::from_secs(percent) && refresh <= now + Duration::from_secs(percent + 2));
+
+            assert!(cache.take_refreshes(refresh - Duration::from_millis(1), |_| true).is_empty());
+            assert_eq!(cache.take_refreshes(refresh, |_| true), vec![a(1, 100)]);
+        }
+        assert!(cache.next_refresh(|_| true).is_none());
+
+        // only for records in use
+        cache.insert(a(1, 100), now);
+        assert!(cache.next_refresh(|x| x.r#type() == ResourceType::PTR).is_none());
+    }
+
+    #[test]
+    fn evict_when_full() {
+        let now = Instant::now();
+        let mut cache = Cache::new();
+
+        for index in 0..MAX_RECORDS - 1 {
+            let name = format!("host-{}.local", index);
+            let ttl = if index == 99 { 100 } else { 1000 };
+            cache.insert(ResourceRecord::new(&name, ttl, ResourceRecordData::A(Ipv4Addr::LOCALHOST)), now);
+        }
+        cache.insert(a(1, 200), now);
+        assert_eq!(cache.records(now).len(), MAX_RECORDS);
+
+        // refreshing a known record does not evict
+        cache.insert(a(1, 200), now + Duration::from_secs(1));
+        assert_eq!(cache.records(now).len(), MAX_RECORDS);
+        assert_eq!(cache.get("host-99.local", ResourceType::A, now).len(), 1);
+
+        // the one closest to expiry makes room
+        cache.insert(a(2, 200), now);
+        assert_eq!(cache.records(now).len(), MAX_RECORDS);
+        assert!(cache.get("host-99.local", ResourceType::A, now).is_empty());
+        assert_eq!(cache.get("host.local", ResourceType::A, now).len(), 2);
+    }
+
+    #[test]
+    fn known_answers() {
+        let now = Instant::now();
+        let mut cache = Cache::new();
+        cache.insert(a(1, 100), now);
+        cache.insert(a(2, 200), now);
+
+        let question = Question::new("host.local", ResourceType::A, false);
+        assert_eq!(cache.known_answers(&question, now + Duration::from_secs(50)), vec![a(1, 50), a(2, 150)]);
+        assert_eq!(cache.known_answers(&question, now + Duration::from_secs(51)), vec![a(2, 149)]);
+
+        cache.insert(a(3, 100).with_cache_flush(true), now);
+        assert!(cache.known_answers(&question, now).iter().all(|x| !x.cache_flush()));
+    }
+}
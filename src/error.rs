@@ -0,0 +1,25 @@
+use std::io;
+
+use thiserror::Error;
+
+use super::packet::ParseError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid hostname {0:?}, expected a single dns label")]
+    InvalidHostname(String),
+    #[error("Invalid service {0:?}, labels are limited to 63 octets, names and TXT strings to 255")]
+    InvalidService(String),
+    #[error("No usable network interface")]
+    NoInterface,
+    #[error("Failed to bind socket: {0}")]
+    Bind(#[source] io::Error),
+    #[error("Malformed packet: {0}")]
+    MalformedPacket(#[from] ParseError),
+    #[error("Server has stopped")]
+    Stopped,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
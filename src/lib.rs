@@ -1,7 +1,17 @@
+mod browser;
+mod cache;
+mod error;
 mod multicast;
 mod packet;
 mod server;
 mod service;
+mod ttl;
+mod watcher;
 
-pub use server::Server;
+pub use browser::{BrowseEvent, Browser, BrowserHandle, ServiceInfo};
+pub use cidr_utils::cidr::IpCidr;
+pub use error::{Error, Result};
+pub use packet::{Class, Header, HeaderFlags, Name, Packet, ParseError, Question, ResourceRecord, ResourceRecordData, ResourceType};
+pub use server::{InterfaceSelector, NameEvent, Server, ServerBuilder, ServerHandle};
 pub use service::Service;
+pub use ttl::Ttl;
@@ -1,3 +1,14 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::debug;
+use tokio::{sync::mpsc, task};
+
+use crate::error::Result;
+
 #[cfg(unix)]
 mod unix;
 #[cfg(unix)]
@@ -9,12 +20,69 @@ mod windows;
 pub use self::windows::MulticastSocket;
 
 #[cfg(target_os = "linux")]
-type InterfaceType = i32;
+pub type InterfaceType = i32;
 #[cfg(any(target_os = "macos", target_os = "windows"))]
-type InterfaceType = u32;
+pub type InterfaceType = u32;
 
 pub struct Message {
     pub data: Vec<u8>,
-    pub sender: std::net::SocketAddrV4,
+    pub sender: std::net::SocketAddr,
     pub interface: InterfaceType,
 }
+
+pub const MULTICAST_ADDR_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+pub const MULTICAST_ADDR_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb)), 5353);
+// largest packet over jumbo frames (rfc6762 17)
+pub const MAX_PACKET_SIZE: usize = 9000;
+
+/// Sockets joined to the mdns group of each address family
+pub struct Sockets {
+    v4: Arc<MulticastSocket>,
+    v6: Option<Arc<MulticastSocket>>,
+}
+
+impl Sockets {
+    pub async fn open(interfaces: &[if_addrs::Interface]) -> Result<Self> {
+        let v4 = Arc::new(MulticastSocket::new(MULTICAST_ADDR_V4.ip(), MULTICAST_ADDR_V4.port(), interfaces).await?);
+
+        // opened even without ipv6 addresses, so that groups are joined once some appear, continuing with ipv4 only if it can't be bound
+        let v6 = match MulticastSocket::new(MULTICAST_ADDR_V6.ip(), MULTICAST_ADDR_V6.port(), interfaces).await {
+            Ok(socket) => Some(Arc::new(socket)),
+            Err(err) => {
+                debug!("ipv6 disabled: {}", err);
+                None
+            }
+        };
+
+        Ok(Self { v4, v6 })
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&MulticastSocket> {
+        match address {
+            SocketAddr::V4(_) => Some(&self.v4),
+            SocketAddr::V6(_) => self.v6.as_deref(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<MulticastSocket>> {
+        [&self.v4].into_iter().chain(&self.v6)
+    }
+
+    /// Reads from each socket in a task, until reading fails or the receiver is dropped
+    pub fn spawn_readers(&self, sender: mpsc::Sender<io::Result<Message>>) -> Vec<task::JoinHandle<()>> {
+        self.iter()
+            .map(|socket| task::spawn(Self::read_loop(socket.clone(), sender.clone())))
+            .collect()
+    }
+
+    async fn read_loop(socket: Arc<MulticastSocket>, sender: mpsc::Sender<io::Result<Message>>) {
+        loop {
+            let message = socket.read().await;
+            let is_err = message.is_err();
+
+            if sender.send(message).await.is_err() || is_err {
+                break;
+            }
+        }
+    }
+}
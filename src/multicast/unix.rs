@@ -1,45 +1,130 @@
 use std::{
     io::{self, IoSlice, IoSliceMut},
     mem,
-    net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
     os::fd::{AsRawFd, FromRawFd, RawFd},
+    sync::Mutex,
 };
 
-use nix::sys::socket::{self, bind, socket, sockopt, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType, SockaddrIn};
+use log::debug;
+use nix::sys::socket::{
+    self, bind, socket, sockopt, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType, SockaddrIn, SockaddrIn6,
+    SockaddrStorage,
+};
 use tokio::io::unix::AsyncFd;
 
-use super::{InterfaceType, Message};
+use super::{InterfaceType, Message, MAX_PACKET_SIZE};
+use crate::error::{Error, Result};
 
 pub struct MulticastSocket {
     socket: AsyncFd<UdpSocket>,
-    address: SocketAddrV4,
+    address: SocketAddr,
+    joined: Mutex<Vec<if_addrs::Interface>>,
 }
 
 impl MulticastSocket {
-    pub async fn new(multicast_addr: Ipv4Addr, port: u16) -> io::Result<Self> {
+    pub async fn new(multicast_addr: IpAddr, port: u16, interfaces: &[if_addrs::Interface]) -> Result<Self> {
+        let socket = match multicast_addr {
+            IpAddr::V4(_) => Self::bind_v4(port)?,
+            IpAddr::V6(_) => Self::bind_v6(port)?,
+        };
+
+        let socket = unsafe { UdpSocket::from_raw_fd(socket) };
+        socket.set_nonblocking(true)?;
+
+        let result = Self {
+            socket: unsafe { AsyncFd::register(socket).map_err(io::Error::from)? },
+            address: SocketAddr::new(multicast_addr, port),
+            joined: Mutex::new(Vec::new()),
+        };
+        result.set_interfaces(interfaces);
+
+        Ok(result)
+    }
+
+    // returns whether the group was joined on the interface
+    fn join(socket: &UdpSocket, multicast_addr: IpAddr, interface: &if_addrs::Interface, joined: &[if_addrs::Interface]) -> io::Result<bool> {
+        match (multicast_addr, interface.addr.ip(), interface.index) {
+            (IpAddr::V4(multicast_addr), IpAddr::V4(ip), _) => socket.join_multicast_v4(&multicast_addr, &ip)?,
+            // membership is per interface, not per address
+            (IpAddr::V6(multicast_addr), IpAddr::V6(_), Some(index)) => {
+                if joined.iter().any(|x| x.index == Some(index)) {
+                    return Ok(false);
+                }
+                socket.join_multicast_v6(&multicast_addr, index)?
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    // joins the group on given interfaces and addresses, and leaves others
+    pub fn set_interfaces(&self, interfaces: &[if_addrs::Interface]) {
+        let mut joined = self.joined.lock().unwrap();
+
+        for interface in joined.iter().filter(|x| !interfaces.contains(x)) {
+            // fails if interface has gone away, which leaves the group anyway
+            if let Err(err) = self.leave_interface(interface) {
+                debug!("failed to leave {} on {}: {}", self.address.ip(), interface.name, err);
+            }
+        }
+        joined.retain(|x| interfaces.contains(x));
+
+        for interface in interfaces {
+            if joined.contains(interface) {
+                continue;
+            }
+
+            // membership may be left over from an address removed earlier
+            match Self::join(self.socket.get_ref(), self.address.ip(), interface, &joined) {
+                Ok(true) => joined.push(interface.clone()),
+                Ok(false) => {}
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => joined.push(interface.clone()),
+                Err(err) => debug!("failed to join {} on {}: {}", self.address.ip(), interface.name, err),
+            }
+        }
+    }
+
+    fn bind_v4(port: u16) -> Result<RawFd> {
         let socket = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None).map_err(Self::map_err)?;
 
         socket::setsockopt(socket, sockopt::Ipv4PacketInfo, &true).map_err(Self::map_err)?;
         socket::setsockopt(socket, sockopt::ReuseAddr, &true).map_err(Self::map_err)?;
 
         let addr: SockaddrIn = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into();
-        bind(socket, &addr).map_err(Self::map_err)?;
+        bind(socket, &addr).map_err(|err| Error::Bind(Self::map_err(err)))?;
 
-        let socket = unsafe { UdpSocket::from_raw_fd(socket) };
-        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
 
-        let interfaces = if_addrs::get_if_addrs()?;
+    fn bind_v6(port: u16) -> Result<RawFd> {
+        let socket = socket(AddressFamily::Inet6, SockType::Datagram, SockFlag::empty(), None).map_err(Self::map_err)?;
 
-        for interface in interfaces {
-            if let IpAddr::V4(ip) = interface.addr.ip() {
-                socket.join_multicast_v4(&multicast_addr, &ip)?;
-            }
+        socket::setsockopt(socket, sockopt::Ipv6RecvPacketInfo, &true).map_err(Self::map_err)?;
+        socket::setsockopt(socket, sockopt::Ipv6V6Only, &true).map_err(Self::map_err)?;
+        socket::setsockopt(socket, sockopt::ReuseAddr, &true).map_err(Self::map_err)?;
+
+        let addr: SockaddrIn6 = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into();
+        bind(socket, &addr).map_err(|err| Error::Bind(Self::map_err(err)))?;
+
+        Ok(socket)
+    }
+
+    pub fn leave(&self) -> io::Result<()> {
+        for interface in self.joined.lock().unwrap().drain(..) {
+            self.leave_interface(&interface)?;
         }
 
-        Ok(Self {
-            socket: AsyncFd::new(socket)?,
-            address: SocketAddrV4::new(multicast_addr, port),
-        })
+        Ok(())
+    }
+
+    fn leave_interface(&self, interface: &if_addrs::Interface) -> io::Result<()> {
+        match (self.address.ip(), interface.addr.ip(), interface.index) {
+            (IpAddr::V4(multicast_addr), IpAddr::V4(ip), _) => self.socket.get_ref().leave_multicast_v4(&multicast_addr, &ip),
+            (IpAddr::V6(multicast_addr), _, Some(index)) => self.socket.get_ref().leave_multicast_v6(&multicast_addr, index),
+            _ => Ok(()),
+        }
     }
 
     pub async fn read(&self) -> io::Result<Message> {
@@ -47,42 +132,60 @@ impl MulticastSocket {
             let mut guard = self.socket.readable().await?;
 
             match guard.try_io(|socket| Self::read_inner(socket.as_raw_fd())) {
-                Ok(result) => return result,
+                Ok(Ok(Some(message))) => return Ok(message),
+                Ok(Ok(None)) => continue,
+                Ok(Err(err)) => return Err(err),
                 Err(_) => continue,
             }
         }
     }
 
-    fn read_inner(fd: RawFd) -> io::Result<Message> {
-        let mut buf = vec![0; 1024];
-        let mut control_buffer = nix::cmsg_space!(libc::in_pktinfo);
+    // returns none for truncated packets, which are dropped
+    fn read_inner(fd: RawFd) -> io::Result<Option<Message>> {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let mut control_buffer = nix::cmsg_space!(libc::in_pktinfo, libc::in6_pktinfo);
 
-        let msg = socket::recvmsg(fd, &mut [IoSliceMut::new(&mut buf)], Some(&mut control_buffer), MsgFlags::empty()).map_err(Self::map_err)?;
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let msg = socket::recvmsg(fd, &mut iov, Some(&mut control_buffer), MsgFlags::empty()).map_err(Self::map_err)?;
 
-        let sender = msg.address.map(|x: SockaddrIn| x.into()).unwrap();
+        let sender = msg
+            .address
+            .and_then(|x: SockaddrStorage| match (x.as_sockaddr_in(), x.as_sockaddr_in6()) {
+                (Some(x), _) => Some(SocketAddr::V4((*x).into())),
+                (_, Some(x)) => Some(SocketAddr::V6((*x).into())),
+                _ => None,
+            })
+            .unwrap();
+        let read_bytes = msg.bytes;
 
-        let interface = msg.cmsgs().find_map(|cmsg| {
-            if let ControlMessageOwned::Ipv4PacketInfo(pktinfo) = cmsg {
-                Some(pktinfo.ipi_ifindex)
-            } else {
-                None
-            }
+        if msg.flags.contains(MsgFlags::MSG_TRUNC) {
+            debug!("dropping packet from {} larger than {} bytes", sender, MAX_PACKET_SIZE);
+
+            return Ok(None);
+        }
+
+        let interface = msg.cmsgs().find_map(|cmsg| match cmsg {
+            ControlMessageOwned::Ipv4PacketInfo(pktinfo) => Some(pktinfo.ipi_ifindex as InterfaceType),
+            ControlMessageOwned::Ipv6PacketInfo(pktinfo) => Some(pktinfo.ipi6_ifindex as InterfaceType),
+            _ => None,
         });
 
-        Ok(Message {
+        buf.truncate(read_bytes);
+
+        Ok(Some(Message {
             data: buf,
             sender,
             interface: interface.unwrap(),
-        })
+        }))
     }
 
-    pub async fn write(&mut self, data: &[u8], interface: InterfaceType) -> io::Result<usize> {
+    pub async fn write(&self, data: &[u8], interface: InterfaceType) -> io::Result<usize> {
         let address = self.address;
 
         self.write_to(data, interface, &address).await
     }
 
-    pub async fn write_to(&mut self, data: &[u8], interface: InterfaceType, dst_addr: &SocketAddrV4) -> io::Result<usize> {
+    pub async fn write_to(&self, data: &[u8], interface: InterfaceType, dst_addr: &SocketAddr) -> io::Result<usize> {
         loop {
             let mut guard = self.socket.writable().await?;
 
@@ -93,23 +196,32 @@ impl MulticastSocket {
         }
     }
 
-    fn write_inner(fd: RawFd, data: &[u8], interface: InterfaceType, dst_addr: &SocketAddrV4) -> io::Result<usize> {
-        let mut pkt_info: libc::in_pktinfo = unsafe { mem::zeroed() };
-        pkt_info.ipi_ifindex = interface;
+    fn write_inner(fd: RawFd, data: &[u8], interface: InterfaceType, dst_addr: &SocketAddr) -> io::Result<usize> {
+        let iov = [IoSlice::new(data)];
 
-        let dst_addr = SockaddrIn::from(*dst_addr);
+        match dst_addr {
+            SocketAddr::V4(dst_addr) => {
+                let mut pkt_info: libc::in_pktinfo = unsafe { mem::zeroed() };
+                pkt_info.ipi_ifindex = interface as _;
 
-        socket::sendmsg(
-            fd,
-            &[IoSlice::new(data)],
-            &[ControlMessage::Ipv4PacketInfo(&pkt_info)],
-            MsgFlags::empty(),
-            Some(&dst_addr),
-        )
+                let dst_addr = SockaddrIn::from(*dst_addr);
+
+                socket::sendmsg(fd, &iov, &[ControlMessage::Ipv4PacketInfo(&pkt_info)], MsgFlags::empty(), Some(&dst_addr))
+            }
+            SocketAddr::V6(dst_addr) => {
+                let mut pkt_info: libc::in6_pktinfo = unsafe { mem::zeroed() };
+                pkt_info.ipi6_ifindex = interface as _;
+
+                let dst_addr = SockaddrIn6::from(*dst_addr);
+
+                socket::sendmsg(fd, &iov, &[ControlMessage::Ipv6PacketInfo(&pkt_info)], MsgFlags::empty(), Some(&dst_addr))
+            }
+        }
         .map_err(Self::map_err)
     }
 
     fn map_err(err: nix::Error) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, err)
+        // keep errno so that EAGAIN is reported as WouldBlock
+        io::Error::from(err)
     }
 }
@@ -4,34 +4,49 @@ use std::{
     collections::HashMap,
     ffi::CStr,
     io,
-    mem::{size_of, size_of_val, zeroed},
-    net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket},
+    mem::{align_of, size_of, size_of_val, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
     os::windows::io::{AsRawSocket, FromRawSocket},
     ptr::null_mut,
     str::FromStr,
-    sync::Once,
+    sync::{Mutex, Once},
 };
 
+use log::debug;
 use tokio::task;
 use windows::{
     core::PSTR,
     Win32::{
         NetworkManagement::IpHelper::{GetAdaptersInfo, IP_ADAPTER_INFO},
         Networking::WinSock::{
-            bind, setsockopt, socket, WSAGetLastError, ADDRESS_FAMILY, AF_INET, CMSGHDR, IN_PKTINFO, IPPROTO_IP, IPPROTO_UDP, IP_PKTINFO,
-            SOCKADDR_IN, SOCKET, SOCK_DGRAM, SOL_SOCKET, SO_REUSEADDR, WSABUF, WSAMSG,
+            bind, setsockopt, socket, WSAGetLastError, ADDRESS_FAMILY, AF_INET, AF_INET6, CMSGHDR, IN6_PKTINFO, IN_PKTINFO, IPPROTO_IP, IPPROTO_IPV6,
+            IPPROTO_UDP, IPV6_PKTINFO, IPV6_V6ONLY, IP_PKTINFO, SOCKADDR_IN, SOCKADDR_IN6, SOCKADDR_STORAGE, SOCKET, SOCK_DGRAM, SOL_SOCKET,
+            SO_REUSEADDR, WSABUF, WSAEMSGSIZE, WSAMSG,
         },
     },
 };
 
 use wsa::{WSARecvMsg, WSASendMsg};
 
-use super::{InterfaceType, Message};
+use super::{InterfaceType, Message, MAX_PACKET_SIZE};
+use crate::error::{Error, Result};
+
+// WSA_CMSGHDR_ALIGN and WSA_CMSGDATA_ALIGN, as the natural alignment is that of the header's SIZE_T length
+const fn cmsg_align(length: usize) -> usize {
+    (length + align_of::<CMSGHDR>() - 1) & !(align_of::<CMSGHDR>() - 1)
+}
+
+// WSA_CMSG_DATA
+const CMSG_DATA_OFFSET: usize = cmsg_align(size_of::<CMSGHDR>());
+// WSA_CMSG_SPACE of the larger packet info
+const CONTROL_BUFFER_SIZE: usize = cmsg_align(CMSG_DATA_OFFSET + cmsg_align(size_of::<IN6_PKTINFO>()));
 
 pub struct MulticastSocket {
     socket: UdpSocket,
-    address: SocketAddrV4,
-    interfaces: HashMap<InterfaceType, Ipv4Addr>,
+    address: SocketAddr,
+    joined: Mutex<Vec<if_addrs::Interface>>,
+    // source address of each interface for ipv4 packet info
+    interfaces: Mutex<HashMap<InterfaceType, Ipv4Addr>>,
 }
 
 fn init() {
@@ -67,50 +82,141 @@ unsafe fn get_interfaces() -> HashMap<InterfaceType, Ipv4Addr> {
 }
 
 impl MulticastSocket {
-    pub async fn new(multicast_addr: Ipv4Addr, port: u16) -> io::Result<Self> {
+    pub async fn new(multicast_addr: IpAddr, port: u16, interfaces: &[if_addrs::Interface]) -> Result<Self> {
         init();
-        let socket = unsafe {
-            let socket = socket(AF_INET.0 as _, SOCK_DGRAM as _, IPPROTO_UDP.0 as _);
+        let socket = match multicast_addr {
+            IpAddr::V4(_) => unsafe { Self::bind_v4(port)? },
+            IpAddr::V6(_) => unsafe { Self::bind_v6(port)? },
+        };
+
+        let result = Self {
+            socket,
+            address: SocketAddr::new(multicast_addr, port),
+            joined: Mutex::new(Vec::new()),
+            interfaces: Mutex::new(HashMap::new()),
+        };
+        result.set_interfaces(interfaces);
 
-            setsockopt(socket, IPPROTO_IP as _, IP_PKTINFO as _, Some(&[1, 0, 0, 0]));
-            setsockopt(socket, SOL_SOCKET as _, SO_REUSEADDR as _, Some(&[1, 0, 0, 0]));
+        Ok(result)
+    }
 
-            let addr = SOCKADDR_IN {
-                sin_family: ADDRESS_FAMILY(AF_INET.0 as _),
-                sin_port: port.to_be(),
-                sin_addr: Ipv4Addr::UNSPECIFIED.into(),
-                sin_zero: [Default::default(); 8],
-            };
-            let r = bind(socket, &addr as *const SOCKADDR_IN as _, size_of_val(&addr) as i32);
-            if r != 0 {
-                return Err(io::Error::last_os_error());
-            }
+    unsafe fn bind_v4(port: u16) -> Result<UdpSocket> {
+        let socket = socket(AF_INET.0 as _, SOCK_DGRAM as _, IPPROTO_UDP.0 as _);
 
-            UdpSocket::from_raw_socket(socket.0 as _)
+        setsockopt(socket, IPPROTO_IP as _, IP_PKTINFO as _, Some(&[1, 0, 0, 0]));
+        setsockopt(socket, SOL_SOCKET as _, SO_REUSEADDR as _, Some(&[1, 0, 0, 0]));
+
+        let addr = SOCKADDR_IN {
+            sin_family: ADDRESS_FAMILY(AF_INET.0 as _),
+            sin_port: port.to_be(),
+            sin_addr: Ipv4Addr::UNSPECIFIED.into(),
+            sin_zero: [Default::default(); 8],
         };
+        let r = bind(socket, &addr as *const SOCKADDR_IN as _, size_of_val(&addr) as i32);
+        if r != 0 {
+            return Err(Error::Bind(io::Error::last_os_error()));
+        }
+
+        Ok(UdpSocket::from_raw_socket(socket.0 as _))
+    }
+
+    unsafe fn bind_v6(port: u16) -> Result<UdpSocket> {
+        let socket = socket(AF_INET6.0 as _, SOCK_DGRAM as _, IPPROTO_UDP.0 as _);
+
+        setsockopt(socket, IPPROTO_IPV6.0 as _, IPV6_PKTINFO as _, Some(&[1, 0, 0, 0]));
+        setsockopt(socket, IPPROTO_IPV6.0 as _, IPV6_V6ONLY as _, Some(&[1, 0, 0, 0]));
+        setsockopt(socket, SOL_SOCKET as _, SO_REUSEADDR as _, Some(&[1, 0, 0, 0]));
 
-        let interfaces = if_addrs::get_if_addrs()?;
+        let mut addr = zeroed::<SOCKADDR_IN6>();
+        addr.sin6_family = ADDRESS_FAMILY(AF_INET6.0 as _);
+        addr.sin6_port = port.to_be();
+        addr.sin6_addr = Ipv6Addr::UNSPECIFIED.into();
+
+        let r = bind(socket, &addr as *const SOCKADDR_IN6 as _, size_of_val(&addr) as i32);
+        if r != 0 {
+            return Err(Error::Bind(io::Error::last_os_error()));
+        }
+
+        Ok(UdpSocket::from_raw_socket(socket.0 as _))
+    }
+
+    // returns whether the group was joined on the interface
+    fn join(socket: &UdpSocket, multicast_addr: IpAddr, interface: &if_addrs::Interface, joined: &[if_addrs::Interface]) -> io::Result<bool> {
+        match (multicast_addr, interface.addr.ip(), interface.index) {
+            (IpAddr::V4(multicast_addr), IpAddr::V4(ip), _) => socket.join_multicast_v4(&multicast_addr, &ip)?,
+            // membership is per interface, not per address
+            (IpAddr::V6(multicast_addr), IpAddr::V6(_), Some(index)) => {
+                if joined.iter().any(|x| x.index == Some(index)) {
+                    return Ok(false);
+                }
+                socket.join_multicast_v6(&multicast_addr, index)?
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    // joins the group on given interfaces and addresses, and leaves others
+    pub fn set_interfaces(&self, interfaces: &[if_addrs::Interface]) {
+        let mut joined = self.joined.lock().unwrap();
+
+        for interface in joined.iter().filter(|x| !interfaces.contains(x)) {
+            // fails if address has gone away, which leaves the group anyway
+            if let Err(err) = self.leave_interface(interface) {
+                debug!("failed to leave {} on {}: {}", self.address.ip(), interface.name, err);
+            }
+        }
+        joined.retain(|x| interfaces.contains(x));
 
         for interface in interfaces {
-            if let IpAddr::V4(ip) = interface.addr.ip() {
-                socket.join_multicast_v4(&multicast_addr, &ip)?;
+            if joined.contains(interface) {
+                continue;
+            }
+
+            match Self::join(&self.socket, self.address.ip(), interface, &joined) {
+                Ok(true) => joined.push(interface.clone()),
+                Ok(false) => {}
+                Err(err) => debug!("failed to join {} on {}: {}", self.address.ip(), interface.name, err),
             }
         }
 
-        Ok(Self {
-            socket,
-            address: SocketAddrV4::new(multicast_addr, port),
-            interfaces: unsafe { get_interfaces() },
-        })
+        *self.interfaces.lock().unwrap() = unsafe { get_interfaces() };
+    }
+
+    pub fn leave(&self) -> io::Result<()> {
+        for interface in self.joined.lock().unwrap().drain(..) {
+            self.leave_interface(&interface)?;
+        }
+
+        Ok(())
+    }
+
+    fn leave_interface(&self, interface: &if_addrs::Interface) -> io::Result<()> {
+        match (self.address.ip(), interface.addr.ip(), interface.index) {
+            (IpAddr::V4(multicast_addr), IpAddr::V4(ip), _) => self.socket.leave_multicast_v4(&multicast_addr, &ip),
+            (IpAddr::V6(multicast_addr), _, Some(index)) => self.socket.leave_multicast_v6(&multicast_addr, index),
+            _ => Ok(()),
+        }
     }
 
     pub async fn read(&self) -> io::Result<Message> {
+        loop {
+            if let Some(message) = self.read_inner().await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    // returns none for truncated packets, which are dropped
+    async fn read_inner(&self) -> io::Result<Option<Message>> {
         let socket = self.socket.as_raw_socket();
 
-        let (r, data_buffer, origin_address, control_buffer, read_bytes) = task::spawn_blocking(move || unsafe {
-            let mut data_buffer = vec![0; 1024];
-            let mut origin_address = zeroed::<SOCKADDR_IN>();
-            let mut control_buffer = [0; size_of::<CMSGHDR>() + size_of::<IN_PKTINFO>()];
+        let (r, data_buffer, origin_address, control_buffer, control_len, read_bytes) = task::spawn_blocking(move || unsafe {
+            let mut data_buffer = vec![0; MAX_PACKET_SIZE];
+            // large enough for either address family
+            let mut origin_address = zeroed::<SOCKADDR_STORAGE>();
+            let mut control_buffer = [0; CONTROL_BUFFER_SIZE];
             let mut read_bytes = 0;
 
             let mut data = WSABUF {
@@ -124,7 +230,7 @@ impl MulticastSocket {
             };
 
             let mut wsa_msg = WSAMSG {
-                name: &mut origin_address as *mut SOCKADDR_IN as _,
+                name: &mut origin_address as *mut SOCKADDR_STORAGE as _,
                 namelen: size_of_val(&origin_address) as _,
                 lpBuffers: &mut data,
                 Control: control,
@@ -134,67 +240,159 @@ impl MulticastSocket {
 
             let r = (WSARecvMsg.unwrap())(SOCKET(socket as _), &mut wsa_msg, &mut read_bytes, null_mut(), None);
 
-            (r, data_buffer, origin_address, control_buffer, read_bytes)
+            // set to the length of received control messages
+            let control_len = (wsa_msg.Control.len as usize).min(control_buffer.len());
+
+            (r, data_buffer, origin_address, control_buffer, control_len, read_bytes)
         })
         .await?;
 
         if r != 0 {
             let error = unsafe { WSAGetLastError() };
+            if error == WSAEMSGSIZE {
+                debug!("dropping packet larger than {} bytes", MAX_PACKET_SIZE);
+
+                return Ok(None);
+            }
+
             return Err(io::Error::from_raw_os_error(error.0));
         }
 
-        let sender = SocketAddrV4::new(origin_address.sin_addr.into(), origin_address.sin_port);
+        let sender = unsafe {
+            if origin_address.ss_family.0 == AF_INET6.0 as _ {
+                let origin_address = &*(&origin_address as *const SOCKADDR_STORAGE as *const SOCKADDR_IN6);
+                let sender = SocketAddrV6::new(
+                    origin_address.sin6_addr.into(),
+                    u16::from_be(origin_address.sin6_port),
+                    0,
+                    origin_address.Anonymous.sin6_scope_id,
+                );
+
+                SocketAddr::V6(sender)
+            } else {
+                let origin_address = &*(&origin_address as *const SOCKADDR_STORAGE as *const SOCKADDR_IN);
+
+                SocketAddr::V4(SocketAddrV4::new(origin_address.sin_addr.into(), u16::from_be(origin_address.sin_port)))
+            }
+        };
+
+        let Some(interface) = Self::packet_interface(&control_buffer[..control_len]) else {
+            debug!("dropping packet from {} without packet info", sender);
 
-        let pktinfo = unsafe { &*(control_buffer[size_of::<CMSGHDR>()..].as_ptr() as *const IN_PKTINFO) };
+            return Ok(None);
+        };
 
-        Ok(Message {
+        Ok(Some(Message {
             data: data_buffer[..(read_bytes as usize)].into(),
             sender,
-            interface: pktinfo.ipi_ifindex,
-        })
+            interface,
+        }))
     }
 
-    pub async fn write(&mut self, data: &[u8], interface: InterfaceType) -> io::Result<usize> {
+    // interface index of the IP_PKTINFO or IPV6_PKTINFO message, walking headers like WSA_CMSG_FIRSTHDR and WSA_CMSG_NXTHDR do
+    fn packet_interface(control: &[u8]) -> Option<InterfaceType> {
+        let mut offset = 0;
+
+        while offset + size_of::<CMSGHDR>() <= control.len() {
+            let header = unsafe { (control[offset..].as_ptr() as *const CMSGHDR).read_unaligned() };
+            if header.cmsg_len < CMSG_DATA_OFFSET || header.cmsg_len > control.len() - offset {
+                break;
+            }
+
+            let data = &control[offset + CMSG_DATA_OFFSET..offset + header.cmsg_len];
+            let level = header.cmsg_level;
+            let r#type = header.cmsg_type;
+
+            if level == IPPROTO_IP as i32 && r#type == IP_PKTINFO as i32 && data.len() >= size_of::<IN_PKTINFO>() {
+                return Some(unsafe { (data.as_ptr() as *const IN_PKTINFO).read_unaligned() }.ipi_ifindex);
+            }
+            if level == IPPROTO_IPV6.0 && r#type == IPV6_PKTINFO as i32 && data.len() >= size_of::<IN6_PKTINFO>() {
+                return Some(unsafe { (data.as_ptr() as *const IN6_PKTINFO).read_unaligned() }.ipi6_ifindex);
+            }
+
+            offset += cmsg_align(header.cmsg_len);
+        }
+
+        None
+    }
+
+    pub async fn write(&self, data: &[u8], interface: InterfaceType) -> io::Result<usize> {
         let address = self.address;
 
         self.write_to(data, interface, &address).await
     }
 
-    pub async fn write_to(&mut self, data: &[u8], interface: InterfaceType, dst_addr: &SocketAddrV4) -> io::Result<usize> {
+    pub async fn write_to(&self, data: &[u8], interface: InterfaceType, dst_addr: &SocketAddr) -> io::Result<usize> {
         let mut data = WSABUF {
             buf: PSTR::from_raw(data.as_ptr() as *mut _),
             len: data.len() as _,
         };
 
-        let mut control_buffer = [0; size_of::<CMSGHDR>() + size_of::<IN_PKTINFO>()];
-        unsafe {
-            *(control_buffer[..size_of::<CMSGHDR>()].as_ptr() as *mut CMSGHDR) = CMSGHDR {
-                cmsg_len: size_of::<CMSGHDR>() + size_of::<IN_PKTINFO>(),
-                cmsg_level: IPPROTO_IP as _,
-                cmsg_type: IP_PKTINFO as _,
-            };
-
-            *(control_buffer[size_of::<CMSGHDR>()..].as_ptr() as *mut IN_PKTINFO) = IN_PKTINFO {
-                ipi_addr: (*self.interfaces.get(&interface).unwrap()).into(),
-                ipi_ifindex: interface,
+        let mut control_buffer = [0; size_of::<CMSGHDR>() + size_of::<IN6_PKTINFO>()];
+        let mut destination = unsafe { zeroed::<SOCKADDR_STORAGE>() };
+
+        let (control_len, destination_len) = unsafe {
+            match dst_addr {
+                SocketAddr::V4(dst_addr) => {
+                    *(control_buffer[..size_of::<CMSGHDR>()].as_ptr() as *mut CMSGHDR) = CMSGHDR {
+                        cmsg_len: size_of::<CMSGHDR>() + size_of::<IN_PKTINFO>(),
+                        cmsg_level: IPPROTO_IP as _,
+                        cmsg_type: IP_PKTINFO as _,
+                    };
+
+                    let ip = self
+                        .interfaces
+                        .lock()
+                        .unwrap()
+                        .get(&interface)
+                        .copied()
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+                    *(control_buffer[size_of::<CMSGHDR>()..].as_ptr() as *mut IN_PKTINFO) = IN_PKTINFO {
+                        ipi_addr: ip.into(),
+                        ipi_ifindex: interface,
+                    };
+
+                    *(&mut destination as *mut SOCKADDR_STORAGE as *mut SOCKADDR_IN) = SOCKADDR_IN {
+                        sin_family: ADDRESS_FAMILY(AF_INET.0 as _),
+                        sin_port: dst_addr.port().to_be(),
+                        sin_addr: (*dst_addr.ip()).into(),
+                        sin_zero: [Default::default(); 8],
+                    };
+
+                    (size_of::<CMSGHDR>() + size_of::<IN_PKTINFO>(), size_of::<SOCKADDR_IN>())
+                }
+                SocketAddr::V6(dst_addr) => {
+                    *(control_buffer[..size_of::<CMSGHDR>()].as_ptr() as *mut CMSGHDR) = CMSGHDR {
+                        cmsg_len: size_of::<CMSGHDR>() + size_of::<IN6_PKTINFO>(),
+                        cmsg_level: IPPROTO_IPV6.0 as _,
+                        cmsg_type: IPV6_PKTINFO as _,
+                    };
+
+                    // source address is chosen by the stack for the interface
+                    *(control_buffer[size_of::<CMSGHDR>()..].as_ptr() as *mut IN6_PKTINFO) = IN6_PKTINFO {
+                        ipi6_addr: Ipv6Addr::UNSPECIFIED.into(),
+                        ipi6_ifindex: interface,
+                    };
+
+                    let address = &mut *(&mut destination as *mut SOCKADDR_STORAGE as *mut SOCKADDR_IN6);
+                    address.sin6_family = ADDRESS_FAMILY(AF_INET6.0 as _);
+                    address.sin6_port = dst_addr.port().to_be();
+                    address.sin6_addr = (*dst_addr.ip()).into();
+                    address.Anonymous.sin6_scope_id = dst_addr.scope_id();
+
+                    (size_of::<CMSGHDR>() + size_of::<IN6_PKTINFO>(), size_of::<SOCKADDR_IN6>())
+                }
             }
-        }
+        };
 
         let control = WSABUF {
             buf: PSTR::from_raw(control_buffer.as_mut_ptr()),
-            len: control_buffer.len() as _,
-        };
-
-        let mut destination = SOCKADDR_IN {
-            sin_family: ADDRESS_FAMILY(AF_INET.0 as _),
-            sin_port: dst_addr.port(),
-            sin_addr: (*dst_addr.ip()).into(),
-            sin_zero: [Default::default(); 8],
+            len: control_len as _,
         };
 
         let mut wsa_msg = WSAMSG {
             name: &mut destination as *mut _ as *mut _,
-            namelen: size_of_val(&destination) as _,
+            namelen: destination_len as _,
             lpBuffers: &mut data,
             Control: control,
             dwBufferCount: 1,
@@ -1,80 +1,137 @@
 use std::{
+    collections::HashMap,
     convert::TryInto,
     fmt,
     mem::size_of,
     net::{Ipv4Addr, Ipv6Addr},
-    str,
+    ptr, str,
 };
 
-use anyhow::{anyhow, Result};
 use bitflags::bitflags;
 use log::trace;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    #[error("Unexpected end of packet at offset {offset}")]
+    UnexpectedEnd { offset: usize },
+    #[error("Record data length {length} at offset {offset} exceeds packet")]
+    InvalidDataLength { offset: usize, length: usize },
+    #[error("Record data at offset {offset} does not match its length")]
+    DataLengthMismatch { offset: usize },
+    #[error("Character string at offset {offset} overruns record data")]
+    CharacterStringOverrun { offset: usize },
+    #[error("Invalid utf-8 string at offset {offset}")]
+    InvalidUtf8 { offset: usize },
+    #[error("Invalid label type at offset {offset}")]
+    InvalidLabel { offset: usize },
+    #[error("Name exceeds 255 octets at offset {offset}")]
+    NameTooLong { offset: usize },
+    #[error("Forward compression pointer at offset {offset}")]
+    ForwardPointer { offset: usize },
+    #[error("Compression pointer loop at offset {offset}")]
+    PointerLoop { offset: usize },
+}
+
+pub(crate) const MAX_LABEL_LENGTH: usize = 63;
+pub(crate) const MAX_NAME_LENGTH: usize = 255;
+pub(crate) const MAX_STRING_LENGTH: usize = 255;
+
+type Result<T> = std::result::Result<T, ParseError>;
 
 struct ReadStream<'a> {
     buffer: &'a [u8],
     cursor: usize,
+    end: usize,
 }
 
 impl<'a> ReadStream<'a> {
     fn new(buffer: &'a [u8]) -> Self {
-        Self { buffer, cursor: 0 }
+        Self {
+            buffer,
+            cursor: 0,
+            end: buffer.len(),
+        }
     }
 
     fn with_cursor(buffer: &'a [u8], cursor: usize) -> Self {
-        Self { buffer, cursor }
+        Self {
+            buffer,
+            cursor,
+            end: buffer.len(),
+        }
     }
 
-    fn read(&mut self, length: usize) -> &[u8] {
-        let result = &self.buffer[self.cursor..self.cursor + length];
-        self.cursor += length;
+    // stream limited to next `length` bytes, sharing underlying buffer for name pointers
+    fn sub_stream(&self, length: usize) -> Result<Self> {
+        if length > self.remaining() {
+            return Err(ParseError::InvalidDataLength { offset: self.cursor, length });
+        }
 
-        result
+        Ok(Self {
+            buffer: self.buffer,
+            cursor: self.cursor,
+            end: self.cursor + length,
+        })
     }
 
-    fn read_as<T>(&mut self) -> &T {
-        unsafe { &*(self.read(size_of::<T>()).as_ptr() as *const T) }
+    fn remaining(&self) -> usize {
+        self.end.saturating_sub(self.cursor)
     }
 
-    fn read_u8(&mut self) -> u8 {
-        let result = u8::from_be_bytes(self.buffer[self.cursor..self.cursor + size_of::<u8>()].try_into().unwrap());
-        self.cursor += size_of::<u8>();
+    fn read(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.remaining() {
+            return Err(ParseError::UnexpectedEnd { offset: self.cursor });
+        }
 
-        result
-    }
+        let result = &self.buffer[self.cursor..self.cursor + length];
+        self.cursor += length;
 
-    fn read_u16(&mut self) -> u16 {
-        let result = u16::from_be_bytes(self.buffer[self.cursor..self.cursor + size_of::<u16>()].try_into().unwrap());
-        self.cursor += size_of::<u16>();
+        Ok(result)
+    }
 
-        result
+    fn read_as<T>(&mut self) -> Result<T> {
+        Ok(unsafe { ptr::read_unaligned(self.read(size_of::<T>())?.as_ptr() as *const T) })
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let result = u32::from_be_bytes(self.buffer[self.cursor..self.cursor + size_of::<u32>()].try_into().unwrap());
-        self.cursor += size_of::<u32>();
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(u8::from_be_bytes(self.read(size_of::<u8>())?.try_into().unwrap()))
+    }
 
-        result
+    fn read_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.read(size_of::<u16>())?.try_into().unwrap()))
     }
 
-    fn read_u128(&mut self) -> u128 {
-        let result = u128::from_be_bytes(self.buffer[self.cursor..self.cursor + size_of::<u128>()].try_into().unwrap());
-        self.cursor += size_of::<u128>();
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.read(size_of::<u32>())?.try_into().unwrap()))
+    }
 
-        result
+    fn read_u128(&mut self) -> Result<u128> {
+        Ok(u128::from_be_bytes(self.read(size_of::<u128>())?.try_into().unwrap()))
     }
 }
 
 struct WriteStream {
     buffer: Vec<u8>,
+    names: HashMap<Vec<String>, u16>, // lowercased name suffix -> offset, for name compression
 }
 
 impl WriteStream {
     fn new(buffer_capacity: usize) -> Self {
         Self {
             buffer: Vec::with_capacity(buffer_capacity),
+            names: HashMap::new(),
         }
     }
 
+    fn position(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn patch_u16(&mut self, position: usize, data: u16) {
+        self.buffer[position..position + size_of::<u16>()].copy_from_slice(&data.to_be_bytes())
+    }
+
     fn write(&mut self, data: &[u8]) {
         self.buffer.extend(data);
     }
@@ -100,7 +157,7 @@ impl WriteStream {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 #[repr(C)]
 pub struct U16be {
     raw: [u8; 2],
@@ -117,16 +174,24 @@ impl U16be {
 }
 
 bitflags! {
-    struct HeaderFlags: u16 { // in big endian form
-        const RESPONSE = 0b0000_0000_1000_0000;
+    pub struct HeaderFlags: u16 {
+        const RESPONSE = 0x8000;
+        const AUTHORITATIVE = 0x0400;
+        const TRUNCATED = 0x0200;
+        const RECURSION_DESIRED = 0x0100;
+        const RECURSION_AVAILABLE = 0x0080;
     }
 }
 
+const OPCODE_MASK: u16 = 0x7800;
+const OPCODE_SHIFT: u16 = 11;
+const RCODE_MASK: u16 = 0x000f;
+
 #[repr(C)]
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Header {
     id: U16be,
-    flags: HeaderFlags,
+    flags: U16be,
     qd_count: U16be,
     an_count: U16be,
     ns_count: U16be,
@@ -134,15 +199,88 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn is_query(&self) -> bool {
-        !self.flags.contains(HeaderFlags::RESPONSE)
+    pub fn new(id: u16) -> Self {
+        Self {
+            id: U16be::new(id),
+            flags: U16be::new(0),
+            qd_count: U16be::new(0),
+            an_count: U16be::new(0),
+            ns_count: U16be::new(0),
+            ar_count: U16be::new(0),
+        }
     }
 
     pub fn id(&self) -> u16 {
         self.id.get()
     }
+
+    pub fn flags(&self) -> HeaderFlags {
+        HeaderFlags::from_bits_truncate(self.flags.get())
+    }
+
+    pub fn is_query(&self) -> bool {
+        !self.is_response()
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags().contains(HeaderFlags::RESPONSE)
+    }
+
+    pub fn is_authoritative(&self) -> bool {
+        self.flags().contains(HeaderFlags::AUTHORITATIVE)
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags().contains(HeaderFlags::TRUNCATED)
+    }
+
+    pub fn is_recursion_desired(&self) -> bool {
+        self.flags().contains(HeaderFlags::RECURSION_DESIRED)
+    }
+
+    pub fn is_recursion_available(&self) -> bool {
+        self.flags().contains(HeaderFlags::RECURSION_AVAILABLE)
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags.get() & OPCODE_MASK) >> OPCODE_SHIFT) as u8
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags.get() & RCODE_MASK) as u8
+    }
+
+    pub fn with_flags(mut self, flags: HeaderFlags, value: bool) -> Self {
+        let raw = self.flags.get();
+        self.flags = U16be::new(if value { raw | flags.bits() } else { raw & !flags.bits() });
+
+        self
+    }
+
+    pub fn with_opcode(mut self, opcode: u8) -> Self {
+        let raw = self.flags.get() & !OPCODE_MASK;
+        self.flags = U16be::new(raw | ((opcode as u16) << OPCODE_SHIFT) & OPCODE_MASK);
+
+        self
+    }
+
+    pub fn with_rcode(mut self, rcode: u8) -> Self {
+        let raw = self.flags.get() & !RCODE_MASK;
+        self.flags = U16be::new(raw | (rcode as u16) & RCODE_MASK);
+
+        self
+    }
 }
 
+fn read_string(stream: &mut ReadStream, length: usize) -> Result<String> {
+    let offset = stream.cursor;
+
+    Ok(str::from_utf8(stream.read(length)?)
+        .map_err(|_| ParseError::InvalidUtf8 { offset })?
+        .into())
+}
+
+#[derive(Clone, Debug)]
 pub struct Name {
     labels: Vec<String>,
 }
@@ -154,26 +292,56 @@ impl Name {
         }
     }
 
+    // non-empty labels of at most 63 octets, and at most 255 octets in wire format including the root label (rfc1035 2.3.4)
+    pub(crate) fn is_valid(name: &str) -> bool {
+        let labels = name.split('.').map(|x| x.len()).collect::<Vec<_>>();
+
+        labels.iter().all(|x| (1..=MAX_LABEL_LENGTH).contains(x)) && labels.iter().map(|x| x + 1).sum::<usize>() < MAX_NAME_LENGTH
+    }
+
     fn parse(stream: &mut ReadStream) -> Result<Self> {
         let mut labels = Vec::new();
+        let mut name_length = 1; // root label
+        let mut segment_start = stream.cursor;
+        let mut pointer_stream: Option<ReadStream> = None;
+
         loop {
-            let length = stream.read_u8() as usize;
-            if length == 0 {
-                break;
-            }
-            if length & 192 == 192 {
-                let offset_byte = stream.read_u8() as usize;
-                let offset = (length << 8 | offset_byte) & !49152;
+            let current = match pointer_stream.as_mut() {
+                Some(x) => x,
+                None => &mut *stream,
+            };
+
+            let offset = current.cursor;
+            let length = current.read_u8()? as usize;
 
-                let mut new_stream = ReadStream::with_cursor(stream.buffer, offset);
-                let mut result = Name::parse(&mut new_stream)?;
+            match length & 0xc0 {
+                0x00 => {
+                    if length == 0 {
+                        break;
+                    }
 
-                labels.append(&mut result.labels);
+                    name_length += length + 1;
+                    if name_length > MAX_NAME_LENGTH {
+                        return Err(ParseError::NameTooLong { offset });
+                    }
 
-                break;
-            } else {
-                let label = stream.read(length);
-                labels.push(str::from_utf8(label)?.into());
+                    labels.push(read_string(current, length)?);
+                }
+                0xc0 => {
+                    let target = (length & 0x3f) << 8 | current.read_u8()? as usize;
+
+                    // pointers must go strictly backwards, before any label we've already visited, so parsing always terminates
+                    if target > offset {
+                        return Err(ParseError::ForwardPointer { offset });
+                    }
+                    if target >= segment_start {
+                        return Err(ParseError::PointerLoop { offset });
+                    }
+
+                    segment_start = target;
+                    pointer_stream = Some(ReadStream::with_cursor(stream.buffer, target));
+                }
+                _ => return Err(ParseError::InvalidLabel { offset }),
             }
         }
 
@@ -181,8 +349,23 @@ impl Name {
     }
 
     fn write(&self, stream: &mut WriteStream) {
-        for label in &self.labels {
-            let bytes = label.as_bytes();
+        for i in 0..self.labels.len() {
+            let suffix = self.labels[i..].iter().map(|x| x.to_ascii_lowercase()).collect::<Vec<_>>();
+
+            if let Some(offset) = stream.names.get(&suffix) {
+                stream.write_u16(0xc000 | offset);
+
+                return;
+            }
+
+            let position = stream.position();
+            if position < 0x4000 {
+                stream.names.insert(suffix, position as u16);
+            }
+
+            let bytes = self.labels[i].as_bytes();
+            debug_assert!(bytes.len() <= MAX_LABEL_LENGTH, "label {:?} too long", self.labels[i]);
+
             stream.write_u8(bytes.len() as u8);
             stream.write(bytes);
         }
@@ -193,10 +376,19 @@ impl Name {
     pub fn equals(&self, other: &str) -> bool {
         let split = other.split('.').collect::<Vec<_>>();
 
-        self.labels == split
+        self.labels.len() == split.len() && self.labels.iter().zip(split).all(|(x, y)| x.eq_ignore_ascii_case(y))
+    }
+}
+
+// names are compared case-insensitively
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len() && self.labels.iter().zip(&other.labels).all(|(x, y)| x.eq_ignore_ascii_case(y))
     }
 }
 
+impl Eq for Name {}
+
 impl fmt::Display for Name {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.write_str(&self.labels.join("."))
@@ -204,13 +396,14 @@ impl fmt::Display for Name {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ResourceType {
     A,
     PTR,
     TXT,
     AAAA,
     SRV,
+    ANY,
     Unknown(u16),
 }
 
@@ -222,6 +415,7 @@ impl ResourceType {
             16 => Self::TXT,
             28 => Self::AAAA,
             33 => Self::SRV,
+            255 => Self::ANY,
             x => {
                 trace!("Unknown resourcetype {}", x);
 
@@ -231,18 +425,25 @@ impl ResourceType {
     }
 
     fn write(&self, stream: &mut WriteStream) {
-        match self {
-            Self::A => stream.write_u16(1),
-            Self::PTR => stream.write_u16(12),
-            Self::TXT => stream.write_u16(16),
-            Self::AAAA => stream.write_u16(28),
-            Self::SRV => stream.write_u16(33),
-            Self::Unknown(x) => panic!("Cannot write unknown resourcetype {}", x),
+        stream.write_u16((*self).into())
+    }
+}
+
+impl From<ResourceType> for u16 {
+    fn from(r#type: ResourceType) -> Self {
+        match r#type {
+            ResourceType::A => 1,
+            ResourceType::PTR => 12,
+            ResourceType::TXT => 16,
+            ResourceType::AAAA => 28,
+            ResourceType::SRV => 33,
+            ResourceType::ANY => 255,
+            ResourceType::Unknown(x) => x,
         }
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Class {
     IN,
     Unknown(u16),
@@ -256,14 +457,22 @@ impl Class {
         }
     }
 
-    fn write(&self, stream: &mut WriteStream) {
-        match self {
-            Self::IN => stream.write_u16(0x8001), // with cache flush bit
-            Self::Unknown(x) => panic!("Cannot write unknown class {}", x),
+    // top bit is unicast response for questions and cache flush for records (rfc6762 18.12, 18.13)
+    fn write(&self, stream: &mut WriteStream, top_bit: bool) {
+        stream.write_u16(if top_bit { 0x8000 } else { 0 } | u16::from(*self))
+    }
+}
+
+impl From<Class> for u16 {
+    fn from(class: Class) -> Self {
+        match class {
+            Class::IN => 1,
+            Class::Unknown(x) => x,
         }
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Question {
     pub name: Name,
     pub r#type: ResourceType,
@@ -272,11 +481,20 @@ pub struct Question {
 }
 
 impl Question {
+    pub fn new(name: &str, r#type: ResourceType, unicast: bool) -> Self {
+        Self {
+            name: Name::new(name),
+            r#type,
+            class: Class::IN,
+            unicast,
+        }
+    }
+
     fn parse(stream: &mut ReadStream) -> Result<Self> {
         let name = Name::parse(stream)?;
 
-        let r#type = stream.read_u16();
-        let class = stream.read_u16();
+        let r#type = stream.read_u16()?;
+        let class = stream.read_u16()?;
 
         let unicast = class & 0x8000 != 0;
 
@@ -292,11 +510,12 @@ impl Question {
         self.name.write(stream);
 
         self.r#type.write(stream);
-        self.class.write(stream);
+        self.class.write(stream, self.unicast);
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ResourceRecordData {
     A(Ipv4Addr),
     AAAA(Ipv6Addr),
@@ -308,40 +527,56 @@ pub enum ResourceRecordData {
 
 impl ResourceRecordData {
     fn parse(r#type: ResourceType, stream: &mut ReadStream) -> Result<Self> {
-        let length = stream.read_u16() as usize;
+        let length = stream.read_u16()? as usize;
+        let mut data = stream.sub_stream(length)?;
 
-        Ok(match r#type {
-            ResourceType::A => Self::A(Ipv4Addr::from(stream.read_u32())),
-            ResourceType::AAAA => Self::AAAA(Ipv6Addr::from(stream.read_u128())),
-            ResourceType::PTR => Self::PTR(Name::parse(stream)?),
+        let result = match r#type {
+            ResourceType::A => Self::A(Ipv4Addr::from(data.read_u32()?)),
+            ResourceType::AAAA => Self::AAAA(Ipv6Addr::from(data.read_u128()?)),
+            ResourceType::PTR => Self::PTR(Name::parse(&mut data)?),
             ResourceType::TXT => {
                 let mut txt = Vec::new();
-                let end = stream.cursor + length;
-                loop {
-                    let length = stream.read_u8() as usize;
-                    txt.push(str::from_utf8(stream.read(length))?.into());
-
-                    if stream.cursor == end {
-                        break;
+                while data.remaining() > 0 {
+                    let offset = data.cursor;
+                    let length = data.read_u8()? as usize;
+                    if length > data.remaining() {
+                        return Err(ParseError::CharacterStringOverrun { offset });
                     }
+
+                    txt.push(read_string(&mut data, length)?);
                 }
 
                 Self::TXT(txt)
             }
             ResourceType::SRV => Self::SRV {
-                priority: stream.read_u16(),
-                weight: stream.read_u16(),
-                port: stream.read_u16(),
-                target: Name::parse(stream)?,
+                priority: data.read_u16()?,
+                weight: data.read_u16()?,
+                port: data.read_u16()?,
+                target: Name::parse(&mut data)?,
             },
             x => Self::Unknown {
                 r#type: x,
-                data: stream.read(length).into(),
+                data: data.read(length)?.into(),
             },
-        })
+        };
+
+        if data.remaining() != 0 {
+            return Err(ParseError::DataLengthMismatch { offset: stream.cursor });
+        }
+        stream.cursor = data.cursor;
+
+        Ok(result)
+    }
+
+    // uncompressed wire form of rdata, without length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut stream = WriteStream::new(64);
+        self.write(&mut stream);
+
+        stream.buffer.split_off(size_of::<u16>())
     }
 
-    fn r#type(&self) -> ResourceType {
+    pub fn r#type(&self) -> ResourceType {
         match self {
             Self::A(_) => ResourceType::A,
             Self::AAAA(_) => ResourceType::AAAA,
@@ -353,21 +588,25 @@ impl ResourceRecordData {
     }
 
     fn write(&self, stream: &mut WriteStream) {
-        let mut new_stream = WriteStream::new(64);
+        // rdata is written in place so that names inside it can be compressed against the whole packet
+        let length_position = stream.position();
+        stream.write_u16(0);
+
         match self {
             Self::A(x) => {
-                new_stream.write_u32((*x).into());
+                stream.write_u32((*x).into());
             }
             Self::AAAA(x) => {
-                new_stream.write_u128((*x).into());
+                stream.write_u128((*x).into());
             }
-            Self::PTR(x) => x.write(&mut new_stream),
+            Self::PTR(x) => x.write(stream),
             Self::TXT(x) => {
                 for item in x {
                     let bytes = item.as_bytes();
+                    debug_assert!(bytes.len() <= MAX_STRING_LENGTH, "TXT string {:?} too long", item);
 
-                    new_stream.write_u8(bytes.len() as u8);
-                    new_stream.write(bytes);
+                    stream.write_u8(bytes.len() as u8);
+                    stream.write(bytes);
                 }
             }
             Self::SRV {
@@ -376,22 +615,24 @@ impl ResourceRecordData {
                 port,
                 target,
             } => {
-                new_stream.write_u16(*priority);
-                new_stream.write_u16(*weight);
-                new_stream.write_u16(*port);
-                target.write(&mut new_stream);
+                stream.write_u16(*priority);
+                stream.write_u16(*weight);
+                stream.write_u16(*port);
+                target.write(stream);
             }
-            Self::Unknown { data, .. } => new_stream.write(data),
+            Self::Unknown { data, .. } => stream.write(data),
         }
 
-        stream.write_u16(new_stream.buffer.len() as u16);
-        stream.write(&new_stream.buffer);
+        let length = stream.position() - length_position - size_of::<u16>();
+        stream.patch_u16(length_position, length as u16);
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ResourceRecord {
     name: Name,
     class: Class,
+    cache_flush: bool,
     ttl: u32,
     data: ResourceRecordData,
 }
@@ -401,6 +642,7 @@ impl ResourceRecord {
         Self {
             name: Name::new(name),
             class: Class::IN,
+            cache_flush: false,
             ttl,
             data,
         }
@@ -409,26 +651,66 @@ impl ResourceRecord {
     fn parse(stream: &mut ReadStream) -> Result<Self> {
         let name = Name::parse(stream)?;
 
-        let r#type = ResourceType::parse(stream.read_u16());
-        let class = Class::parse(stream.read_u16());
-        let ttl = stream.read_u32();
+        let r#type = ResourceType::parse(stream.read_u16()?);
+        let class = stream.read_u16()?;
+        let ttl = stream.read_u32()?;
 
         let data = ResourceRecordData::parse(r#type, stream)?;
 
-        Ok(ResourceRecord { name, class, ttl, data })
+        Ok(ResourceRecord {
+            name,
+            class: Class::parse(class),
+            cache_flush: class & 0x8000 != 0,
+            ttl,
+            data,
+        })
+    }
+
+    pub fn with_ttl(self, ttl: u32) -> Self {
+        Self { ttl, ..self }
+    }
+
+    /// Sets cache flush bit, which must only be used for unique records
+    pub fn with_cache_flush(self, cache_flush: bool) -> Self {
+        Self { cache_flush, ..self }
+    }
+
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn data(&self) -> &ResourceRecordData {
+        &self.data
+    }
+
+    pub fn r#type(&self) -> ResourceType {
+        self.data.r#type()
+    }
+
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    pub fn cache_flush(&self) -> bool {
+        self.cache_flush
     }
 
     fn write(&self, stream: &mut WriteStream) {
         self.name.write(stream);
 
         self.data.r#type().write(stream);
-        self.class.write(stream);
+        self.class.write(stream, self.cache_flush);
         stream.write_u32(self.ttl);
 
         self.data.write(stream);
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Packet {
     pub header: Header,
     pub questions: Vec<Question>,
@@ -438,20 +720,19 @@ pub struct Packet {
 }
 
 impl Packet {
-    pub fn new_response(
-        id: u16,
+    pub fn new(
+        header: Header,
         questions: Vec<Question>,
         answers: Vec<ResourceRecord>,
         nameservers: Vec<ResourceRecord>,
         additionals: Vec<ResourceRecord>,
     ) -> Self {
         let header = Header {
-            id: U16be::new(id),
-            flags: HeaderFlags::RESPONSE,
             qd_count: U16be::new(questions.len() as u16),
             an_count: U16be::new(answers.len() as u16),
             ns_count: U16be::new(nameservers.len() as u16),
             ar_count: U16be::new(additionals.len() as u16),
+            ..header
         };
 
         Self {
@@ -463,14 +744,27 @@ impl Packet {
         }
     }
 
-    pub fn parse(raw: &[u8]) -> Result<Self> {
-        if raw.len() < size_of::<Header>() {
-            return Err(anyhow!("Buffer too small"));
-        }
+    pub fn new_query(id: u16, questions: Vec<Question>, answers: Vec<ResourceRecord>, nameservers: Vec<ResourceRecord>) -> Self {
+        Self::new(Header::new(id), questions, answers, nameservers, Vec::new())
+    }
 
+    // mdns responses are always authoritative (rfc6762 18.4)
+    pub fn new_response(
+        id: u16,
+        questions: Vec<Question>,
+        answers: Vec<ResourceRecord>,
+        nameservers: Vec<ResourceRecord>,
+        additionals: Vec<ResourceRecord>,
+    ) -> Self {
+        let header = Header::new(id).with_flags(HeaderFlags::RESPONSE | HeaderFlags::AUTHORITATIVE, true);
+
+        Self::new(header, questions, answers, nameservers, additionals)
+    }
+
+    pub fn parse(raw: &[u8]) -> Result<Self> {
         let mut stream = ReadStream::new(raw);
 
-        let header = stream.read_as::<Header>().clone();
+        let header = stream.read_as::<Header>()?;
 
         let questions = (0..header.qd_count.get()).map(|_| Question::parse(&mut stream)).collect::<Result<_>>()?;
         let answers = (0..header.an_count.get())
@@ -495,7 +789,14 @@ impl Packet {
     pub fn write(&self) -> Vec<u8> {
         let mut stream = WriteStream::new(2048);
 
-        stream.write_from(&self.header);
+        let header = Header {
+            qd_count: U16be::new(self.questions.len() as u16),
+            an_count: U16be::new(self.answers.len() as u16),
+            ns_count: U16be::new(self.nameservers.len() as u16),
+            ar_count: U16be::new(self.additionals.len() as u16),
+            ..self.header.clone()
+        };
+        stream.write_from(&header);
 
         self.questions.iter().for_each(|x| x.write(&mut stream));
         self.answers.iter().for_each(|x| x.write(&mut stream));
@@ -508,6 +809,8 @@ impl Packet {
 
 #[cfg(test)]
 mod test {
+    use proptest::prelude::*;
+
     use super::*;
 
     // some tests are copied from https://github.com/librespot-org/libmdns/blob/master/src/dns_parser/parser.rs
@@ -564,10 +867,12 @@ mod test {
         assert!(matches!(packet.answers[0].data, ResourceRecordData::A(_)));
         assert!(packet.answers[0].class == Class::IN);
 
+        // repeated name is written as a compression pointer
+        let compressed = b"\x06%\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x80\x01\xc0\x0c\x00\x01\x80\x01\x00\x00\x04\xf8\x00\x04]\xb8\xd8\"";
         let new_packet = packet.write();
 
-        assert_eq!(new_packet.len(), response.len());
-        assert_eq!(&new_packet, response);
+        assert_eq!(new_packet.len(), compressed.len());
+        assert_eq!(&new_packet, compressed);
 
         Ok(())
     }
@@ -654,4 +959,298 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn header_flags() -> Result<()> {
+        let response = b"\x06%\x81\x80\x00\x00\x00\x00\x00\x00\x00\x00";
+        let header = Packet::parse(response)?.header;
+
+        assert!(header.is_response());
+        assert!(!header.is_authoritative());
+        assert!(!header.is_truncated());
+        assert!(header.is_recursion_desired());
+        assert!(header.is_recursion_available());
+        assert_eq!(header.opcode(), 0);
+        assert_eq!(header.rcode(), 0);
+
+        let header = Header::new(1)
+            .with_flags(HeaderFlags::TRUNCATED | HeaderFlags::AUTHORITATIVE, true)
+            .with_opcode(2)
+            .with_rcode(3);
+
+        assert!(header.is_query());
+        assert!(header.is_authoritative());
+        assert!(header.is_truncated());
+        assert_eq!(header.opcode(), 2);
+        assert_eq!(header.rcode(), 3);
+        assert_eq!(header.flags.get(), 0x1603);
+
+        let header = header.with_flags(HeaderFlags::TRUNCATED, false).with_opcode(0);
+        assert!(!header.is_truncated());
+        assert_eq!(header.flags.get(), 0x0403);
+
+        let packet = Packet::new_response(0, Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        assert_eq!(&packet.write(), b"\x00\x00\x84\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_truncated_packet() {
+        let response =  b"\x06%\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x80\x01\x07example\x03com\x00\x00\x01\x80\x01\x00\x00\x04\xf8\x00\x04]\xb8\xd8\"";
+
+        for length in 0..response.len() {
+            assert!(Packet::parse(&response[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_invalid_data_length() {
+        // rdlength 0xff exceeds packet
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\x04test\x00\x00\x01\x80\x01\x00\x00\x00\x78\x00\xff\xc0\xa8\x01\x01";
+        assert!(matches!(Packet::parse(response), Err(ParseError::InvalidDataLength { .. })));
+
+        // A record with 3 bytes of data
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\x04test\x00\x00\x01\x80\x01\x00\x00\x00\x78\x00\x03\xc0\xa8\x01\x01";
+        assert!(matches!(Packet::parse(response), Err(ParseError::UnexpectedEnd { .. })));
+
+        // A record with 5 bytes of data
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\x04test\x00\x00\x01\x80\x01\x00\x00\x00\x78\x00\x05\xc0\xa8\x01\x01\x00";
+        assert!(matches!(Packet::parse(response), Err(ParseError::DataLengthMismatch { .. })));
+    }
+
+    #[test]
+    fn parse_txt_overrun() {
+        // second string claims 5 bytes, only 3 left in rdata
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\x04test\x00\x00\x10\x80\x01\x00\x00\x00\x78\x00\x07\x02ab\x05cde\x00\x00";
+        assert!(matches!(Packet::parse(response), Err(ParseError::CharacterStringOverrun { .. })));
+    }
+
+    #[test]
+    fn parse_name_pointer_loop() {
+        // answer name is a pointer to itself
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\xc0\x0c\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x01";
+        assert!(matches!(Packet::parse(response), Err(ParseError::PointerLoop { offset: 12 })));
+
+        // label followed by a pointer back to its own start
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\x04test\xc0\x0c\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x01";
+        assert!(matches!(Packet::parse(response), Err(ParseError::PointerLoop { offset: 17 })));
+
+        // two names pointing at each other
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x02\x00\x00\x00\x00\x01a\xc0\x1e\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x01\x01b\xc0\x0c\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x01";
+        assert!(Packet::parse(response).is_err());
+    }
+
+    #[test]
+    fn parse_name_forward_pointer() {
+        let response =
+            b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\xc0\x1a\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x01\x04test\x00";
+        assert!(matches!(Packet::parse(response), Err(ParseError::ForwardPointer { offset: 12 })));
+    }
+
+    #[test]
+    fn parse_name_invalid_label() {
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\x44test\x00\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x01";
+        assert!(matches!(Packet::parse(response), Err(ParseError::InvalidLabel { offset: 12 })));
+    }
+
+    #[test]
+    fn parse_name_too_long() {
+        // chain of names each reusing the previous one, growing past 255 octets
+        let mut query = b"\x00\x00\x00\x00\x00\x05\x00\x00\x00\x00\x00\x00".to_vec();
+        let label = [b'a'; 63];
+
+        query.push(63);
+        query.extend(label);
+        query.push(0);
+        query.extend(b"\x00\x01\x00\x01");
+        let mut previous = 12;
+        for _ in 0..4 {
+            let offset = query.len();
+            query.push(63);
+            query.extend(label);
+            query.extend([0xc0 | (previous >> 8) as u8, previous as u8]);
+            query.extend(b"\x00\x01\x00\x01");
+            previous = offset;
+        }
+
+        // 3 labels (193 octets) are fine, 4 labels (257 octets) are not
+        assert!(matches!(Packet::parse(&query), Err(ParseError::NameTooLong { .. })));
+
+        query[5] = 3;
+        let packet = Packet::parse(&query).unwrap();
+        assert_eq!(packet.questions[2].name.labels.len(), 3);
+    }
+
+    #[test]
+    fn write_compressed_names() -> Result<()> {
+        let hostname = "hostname.local";
+
+        let ptr = ResourceRecord::new("_raop._tcp.local", 3600, ResourceRecordData::PTR(Name::new("test._raop._tcp.local")));
+        let srv = ResourceRecord::new(
+            "test._raop._tcp.local",
+            3600,
+            ResourceRecordData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 1234,
+                target: Name::new(hostname),
+            },
+        )
+        .with_cache_flush(true);
+        let a = ResourceRecord::new(hostname, 3600, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 1))).with_cache_flush(true);
+
+        let packet = Packet::new_response(0, Vec::new(), vec![ptr], Vec::new(), vec![srv, a]);
+        let raw = packet.write();
+
+        let expected = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x02\
+            \x05_raop\x04_tcp\x05local\x00\x00\x0c\x00\x01\x00\x00\x0e\x10\x00\x07\x04test\xc0\x0c\
+            \xc0\x28\x00\x21\x80\x01\x00\x00\x0e\x10\x00\x11\x00\x00\x00\x00\x04\xd2\x08hostname\xc0\x17\
+            \xc0\x41\x00\x01\x80\x01\x00\x00\x0e\x10\x00\x04\xc0\xa8\x01\x01";
+        assert_eq!(&raw, expected);
+
+        let packet2 = Packet::parse(&raw)?;
+        assert_eq!(packet2.answers[0].name.to_string(), "_raop._tcp.local");
+        assert!(matches!(&packet2.answers[0].data, ResourceRecordData::PTR(x) if x.equals("test._raop._tcp.local")));
+        assert_eq!(packet2.additionals[0].name.to_string(), "test._raop._tcp.local");
+        assert!(matches!(&packet2.additionals[0].data, ResourceRecordData::SRV { target, .. } if target.equals(hostname)));
+        assert_eq!(packet2.additionals[1].name.to_string(), hostname);
+        assert!(!packet2.answers[0].cache_flush());
+        assert!(packet2.additionals.iter().all(|x| x.cache_flush()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_compressed_names_case_insensitive() {
+        let a = ResourceRecord::new("Host.local", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 1)));
+        let a2 = ResourceRecord::new("host.LOCAL", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 2)));
+
+        let raw = Packet::new_response(0, Vec::new(), vec![a, a2], Vec::new(), Vec::new()).write();
+
+        assert_eq!(&raw[12..24], b"\x04Host\x05local\x00");
+        assert_eq!(&raw[38..40], b"\xc0\x0c");
+    }
+
+    #[test]
+    fn valid_name() {
+        assert!(Name::is_valid("test._raop._tcp.local"));
+        assert!(Name::is_valid(&format!("{}.local", "a".repeat(63))));
+        assert!(!Name::is_valid(&format!("{}.local", "a".repeat(64))));
+        assert!(!Name::is_valid("test.._tcp.local"));
+
+        // 63 + 63 + 63 + 62 octets of labels, plus 4 length octets and the root label
+        let name = ["a".repeat(63), "b".repeat(63), "c".repeat(63), "d".repeat(62)].join(".");
+        assert!(!Name::is_valid(&name));
+        assert!(Name::is_valid(&name[..name.len() - 1]));
+    }
+
+    fn name_strategy() -> impl Strategy<Value = Name> {
+        prop::collection::vec("[a-zA-Z0-9_-]{1,20}", 1..5).prop_map(|labels| Name { labels })
+    }
+
+    fn resource_type_strategy() -> impl Strategy<Value = ResourceType> {
+        any::<u16>().prop_map(ResourceType::parse)
+    }
+
+    fn resource_record_data_strategy() -> impl Strategy<Value = ResourceRecordData> {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(|x| ResourceRecordData::A(x.into())),
+            any::<[u8; 16]>().prop_map(|x| ResourceRecordData::AAAA(x.into())),
+            name_strategy().prop_map(ResourceRecordData::PTR),
+            prop::collection::vec("\\PC{0,30}", 0..4).prop_map(ResourceRecordData::TXT),
+            (any::<u16>(), any::<u16>(), any::<u16>(), name_strategy()).prop_map(|(priority, weight, port, target)| {
+                ResourceRecordData::SRV {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                }
+            }),
+            (
+                resource_type_strategy().prop_filter("known type", |x| matches!(x, ResourceType::Unknown(_))),
+                prop::collection::vec(any::<u8>(), 0..64)
+            )
+                .prop_map(|(r#type, data)| ResourceRecordData::Unknown { r#type, data }),
+        ]
+    }
+
+    fn class_strategy() -> impl Strategy<Value = Class> {
+        prop_oneof![Just(Class::IN), (0u16..0x8000).prop_map(Class::parse)]
+    }
+
+    fn resource_record_strategy() -> impl Strategy<Value = ResourceRecord> {
+        (
+            name_strategy(),
+            class_strategy(),
+            any::<bool>(),
+            any::<u32>(),
+            resource_record_data_strategy(),
+        )
+            .prop_map(|(name, class, cache_flush, ttl, data)| ResourceRecord {
+                name,
+                class,
+                cache_flush,
+                ttl,
+                data,
+            })
+    }
+
+    fn question_strategy() -> impl Strategy<Value = Question> {
+        (name_strategy(), resource_type_strategy(), class_strategy(), any::<bool>()).prop_map(|(name, r#type, class, unicast)| Question {
+            name,
+            r#type,
+            class,
+            unicast,
+        })
+    }
+
+    // queries and responses with any flags, opcode and rcode
+    fn header_strategy() -> impl Strategy<Value = Header> {
+        (any::<u16>(), any::<u16>(), 0u8..16, 0u8..16).prop_map(|(id, flags, opcode, rcode)| {
+            Header::new(id)
+                .with_flags(HeaderFlags::from_bits_truncate(flags), true)
+                .with_opcode(opcode)
+                .with_rcode(rcode)
+        })
+    }
+
+    fn packet_strategy() -> impl Strategy<Value = Packet> {
+        (
+            header_strategy(),
+            prop::collection::vec(question_strategy(), 0..4),
+            prop::collection::vec(resource_record_strategy(), 0..4),
+            prop::collection::vec(resource_record_strategy(), 0..4),
+            prop::collection::vec(resource_record_strategy(), 0..4),
+        )
+            .prop_map(|(header, questions, answers, nameservers, additionals)| Packet::new(header, questions, answers, nameservers, additionals))
+    }
+
+    proptest! {
+        #[test]
+        fn name_round_trip(name in name_strategy()) {
+            let mut stream = WriteStream::new(256);
+            name.write(&mut stream);
+
+            prop_assert_eq!(Name::parse(&mut ReadStream::new(&stream.buffer))?, name);
+        }
+
+        #[test]
+        fn resource_record_round_trip(record in resource_record_strategy()) {
+            let mut stream = WriteStream::new(256);
+            record.write(&mut stream);
+
+            prop_assert_eq!(ResourceRecord::parse(&mut ReadStream::new(&stream.buffer))?, record);
+        }
+
+        #[test]
+        fn packet_round_trip(packet in packet_strategy()) {
+            prop_assert_eq!(Packet::parse(&packet.write())?, packet);
+        }
+
+        #[test]
+        fn parse_arbitrary_bytes(raw in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Packet::parse(&raw);
+        }
+    }
 }
@@ -0,0 +1,135 @@
+use cidr_utils::cidr::IpCidr;
+
+/// Selects network interfaces by name, index, or addresses within a network
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InterfaceSelector {
+    Name(String),
+    Index(u32),
+    Cidr(IpCidr),
+}
+
+impl InterfaceSelector {
+    fn matches(&self, interface: &if_addrs::Interface) -> bool {
+        match self {
+            Self::Name(name) => interface.name == *name,
+            Self::Index(index) => interface.index == Some(*index),
+            Self::Cidr(cidr) => cidr.contains(interface.ip()),
+        }
+    }
+}
+
+// addresses are used if allowed, or allow list is empty, and their interface
+// has no address that is denied
+#[derive(Clone, Default)]
+pub(super) struct InterfaceFilter {
+    pub allow: Vec<InterfaceSelector>,
+    pub deny: Vec<InterfaceSelector>,
+}
+
+impl InterfaceFilter {
+    pub fn apply(&self, interfaces: Vec<if_addrs::Interface>) -> Vec<if_addrs::Interface> {
+        let denied = interfaces
+            .iter()
+            .filter(|x| self.deny.iter().any(|selector| selector.matches(x)))
+            .map(|x| (x.index, x.name.clone()))
+            .collect::<Vec<_>>();
+
+        interfaces
+            .into_iter()
+            .filter(|x| self.allow.is_empty() || self.allow.iter().any(|selector| selector.matches(x)))
+            .filter(|x| !denied.iter().any(|(index, name)| Self::same_interface(x, *index, name)))
+            .collect()
+    }
+
+    // by index, or by name if the index is unknown
+    fn same_interface(interface: &if_addrs::Interface, index: Option<u32>, name: &str) -> bool {
+        match (interface.index, index) {
+            (Some(a), Some(b)) => a == b,
+            _ => interface.name == name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use if_addrs::{IfAddr, Ifv4Addr, Ifv6Addr, Interface};
+
+    use super::*;
+
+    fn interface(name: &str, index: u32, ip: IpAddr) -> Interface {
+        let addr = match ip {
+            IpAddr::V4(ip) => IfAddr::V4(Ifv4Addr {
+                ip,
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                broadcast: None,
+            }),
+            IpAddr::V6(ip) => IfAddr::V6(Ifv6Addr {
+                ip,
+                netmask: Ipv6Addr::new(0xffff, 0xffff, 0xffff, 0xffff, 0, 0, 0, 0),
+                broadcast: None,
+            }),
+        };
+
+        Interface {
+            name: name.into(),
+            addr,
+            index: Some(index),
+        }
+    }
+
+    fn names(interfaces: Vec<Interface>) -> Vec<String> {
+        interfaces.into_iter().map(|x| x.name).collect()
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let eth0 = interface("eth0", 2, Ipv4Addr::new(192, 168, 1, 1).into());
+        let docker0 = interface("docker0", 3, Ipv4Addr::new(172, 17, 0, 1).into());
+        let tun0 = interface("tun0", 4, Ipv4Addr::new(10, 8, 0, 1).into());
+        let interfaces = vec![eth0, docker0, tun0];
+
+        assert_eq!(
+            names(InterfaceFilter::default().apply(interfaces.clone())),
+            vec!["eth0", "docker0", "tun0"]
+        );
+
+        let filter = InterfaceFilter {
+            allow: Vec::new(),
+            deny: vec![
+                InterfaceSelector::Name("docker0".into()),
+                InterfaceSelector::Cidr(IpCidr::from_str("10.0.0.0/8").unwrap()),
+            ],
+        };
+        assert_eq!(names(filter.apply(interfaces.clone())), vec!["eth0"]);
+
+        let filter = InterfaceFilter {
+            allow: vec![InterfaceSelector::Index(2), InterfaceSelector::Name("tun0".into())],
+            deny: vec![InterfaceSelector::Cidr(IpCidr::from_str("10.8.0.0/16").unwrap())],
+        };
+        assert_eq!(names(filter.apply(interfaces)), vec!["eth0"]);
+    }
+
+    #[test]
+    fn deny_whole_interface() {
+        let eth0_v4 = interface("eth0", 2, Ipv4Addr::new(192, 168, 1, 1).into());
+        let eth0_v6 = interface("eth0", 2, Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).into());
+        let wlan0_v6 = interface("wlan0", 3, Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2).into());
+        let interfaces = vec![eth0_v4, eth0_v6.clone(), wlan0_v6.clone()];
+
+        // the ipv4 network also excludes ipv6 addresses of the same interface
+        let filter = InterfaceFilter {
+            allow: Vec::new(),
+            deny: vec![InterfaceSelector::Cidr(IpCidr::from_str("192.168.1.0/24").unwrap())],
+        };
+        assert_eq!(filter.apply(interfaces.clone()), vec![wlan0_v6.clone()]);
+
+        // allow still selects addresses
+        let filter = InterfaceFilter {
+            allow: vec![InterfaceSelector::Cidr(IpCidr::from_str("fe80::/64").unwrap())],
+            deny: Vec::new(),
+        };
+        assert_eq!(filter.apply(interfaces), vec![eth0_v6, wlan0_v6]);
+    }
+}
@@ -0,0 +1,501 @@
+mod filter;
+mod probe;
+mod responder;
+
+use std::{future, io, net::Ipv4Addr, sync::Arc};
+
+use cidr_utils::cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr};
+use log::{debug, error, trace};
+use rand::Rng;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task,
+    time::{self, Instant},
+};
+
+use super::{
+    error::{Error, Result},
+    multicast::{InterfaceType, Message, Sockets, MULTICAST_ADDR_V4, MULTICAST_ADDR_V6},
+    watcher::InterfaceWatcher,
+    Service, Ttl,
+};
+use filter::InterfaceFilter;
+pub use filter::InterfaceSelector;
+use responder::{Destination, Interface, Outgoing, Responder};
+
+/// Final name of a record set, reported once probing for it has completed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NameEvent {
+    Hostname { requested: String, name: String },
+    Service { requested: String, name: String },
+}
+
+type NameCallback = Arc<dyn Fn(&NameEvent) + Send + Sync>;
+
+pub struct Server {
+    services: Vec<Service>,
+    hostname: String,
+    interfaces: Vec<if_addrs::Interface>,
+    filter: InterfaceFilter,
+    ttl: Ttl,
+    name_callback: Option<NameCallback>,
+}
+
+impl Server {
+    pub fn new(services: Vec<Service>) -> Result<Self> {
+        Self::builder().services(services).build()
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Starts responding in background, the returned handle is used to stop it
+    pub async fn serve(&self) -> Result<ServerHandle> {
+        let sockets = Sockets::open(&self.interfaces).await?;
+
+        let responder = Responder::new(
+            self.hostname.clone(),
+            self.services.clone(),
+            responder_interfaces(&self.interfaces),
+            self.ttl,
+            self.name_callback.clone(),
+            Instant::now(),
+        );
+
+        // without it interfaces found at startup are used
+        let watcher = match InterfaceWatcher::new() {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                debug!("not watching interface changes: {}", err);
+                None
+            }
+        };
+
+        let (commands, receiver) = mpsc::channel(8);
+        task::spawn(Self::serve_loop(sockets, watcher, self.filter.clone(), responder, receiver));
+
+        Ok(ServerHandle { commands })
+    }
+
+    async fn serve_loop(
+        sockets: Sockets,
+        mut watcher: Option<InterfaceWatcher>,
+        filter: InterfaceFilter,
+        mut responder: Responder,
+        mut commands: mpsc::Receiver<Command>,
+    ) {
+        let (sender, mut receiver) = mpsc::channel(32);
+        let readers = sockets.spawn_readers(sender);
+
+        let (reply, result) = match Self::run(&sockets, &mut watcher, &filter, &mut receiver, &mut commands, &mut responder).await {
+            Ok(reply) => (reply, Ok(())),
+            Err(err) => {
+                error!("Server stopped: {}", err);
+
+                // keep the error for shutdown()
+                loop {
+                    match commands.recv().await {
+                        Some(Command::Shutdown(reply)) => break (Some(reply), Err(err)),
+                        Some(_) => continue,
+                        None => break (None, Err(err)),
+                    }
+                }
+            }
+        };
+        for reader in &readers {
+            reader.abort();
+        }
+
+        // best effort, socket may already be unusable
+        for outgoing in responder.flush().into_iter().chain(responder.goodbye()) {
+            if let Err(err) = Self::send(&sockets, outgoing).await {
+                debug!("failed to send on shutdown: {}", err);
+            }
+        }
+
+        for socket in sockets.iter() {
+            if let Err(err) = socket.leave() {
+                debug!("failed to leave multicast group: {}", err);
+            }
+        }
+
+        // wait for readers to release the sockets so they are closed before replying
+        for reader in readers {
+            let _ = reader.await;
+        }
+        drop(sockets);
+
+        if let Some(reply) = reply {
+            let _ = reply.send(result);
+        }
+    }
+
+    // returns on shutdown request, or when all handles are dropped
+    async fn run(
+        sockets: &Sockets,
+        watcher: &mut Option<InterfaceWatcher>,
+        filter: &InterfaceFilter,
+        receiver: &mut mpsc::Receiver<io::Result<Message>>,
+        commands: &mut mpsc::Receiver<Command>,
+        responder: &mut Responder,
+    ) -> Result<Option<oneshot::Sender<Result<()>>>> {
+        loop {
+            let deadline = responder.next_deadline();
+
+            let outgoing = tokio::select! {
+                command = commands.recv() => {
+                    match command {
+                        Some(Command::Register(service)) => {
+                            responder.register(service, Instant::now());
+                            Vec::new()
+                        }
+                        Some(Command::Update(service)) => {
+                            responder.update(service, Instant::now());
+                            Vec::new()
+                        }
+                        Some(Command::Unregister(service)) => responder.unregister(&service),
+                        Some(Command::Shutdown(reply)) => return Ok(Some(reply)),
+                        None => return Ok(None),
+                    }
+                }
+                message = receiver.recv() => {
+                    let message = match message {
+                        Some(message) => message?,
+                        None => return Ok(None),
+                    };
+                    trace!("receive from {}, raw {:?}", message.sender, message.data);
+
+                    responder.handle_message(&message, Instant::now())
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    responder.handle_timer(Instant::now())
+                }
+                result = Self::interface_changed(watcher.as_ref()) => {
+                    match result {
+                        Ok(()) => Self::refresh_interfaces(sockets, filter, responder),
+                        Err(err) => {
+                            debug!("stopped watching interface changes: {}", err);
+                            *watcher = None;
+                            Vec::new()
+                        }
+                    }
+                }
+            };
+
+            for outgoing in outgoing {
+                // interfaces may go away at any time
+                if let Err(err) = Self::send(sockets, outgoing).await {
+                    debug!("failed to send: {}", err);
+                }
+            }
+        }
+    }
+
+    async fn interface_changed(watcher: Option<&InterfaceWatcher>) -> io::Result<()> {
+        match watcher {
+            Some(watcher) => watcher.changed().await,
+            None => future::pending().await,
+        }
+    }
+
+    fn refresh_interfaces(sockets: &Sockets, filter: &InterfaceFilter, responder: &mut Responder) -> Vec<Outgoing> {
+        let interfaces = match scan_interfaces(filter) {
+            Ok(interfaces) => interfaces,
+            Err(err) => {
+                debug!("failed to get interfaces: {}", err);
+                return Vec::new();
+            }
+        };
+
+        for socket in sockets.iter() {
+            socket.set_interfaces(&interfaces);
+        }
+
+        responder.set_interfaces(responder_interfaces(&interfaces), Instant::now())
+    }
+
+    async fn send(sockets: &Sockets, outgoing: Outgoing) -> Result<()> {
+        let data = outgoing.packet.write();
+
+        match outgoing.destination {
+            Destination::Unicast(address, interface) => {
+                trace!("sending unicast to {:?}, raw {:?}", address, data);
+
+                if let Some(socket) = sockets.get(&address) {
+                    socket.write_to(&data, interface, &address).await?;
+                }
+            }
+            Destination::Multicast(group, interface) => {
+                trace!("sending multicast to {} on interface {}, raw {:?}", group, interface, data);
+
+                if let Some(socket) = sockets.get(&group) {
+                    socket.write(&data, interface).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+enum Command {
+    Register(Service),
+    Update(Service),
+    Unregister(Service),
+    Shutdown(oneshot::Sender<Result<()>>),
+}
+
+/// Handle to a running server. Dropping all clones of it stops the server as well, without waiting.
+#[derive(Clone)]
+pub struct ServerHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl ServerHandle {
+    /// Adds a service, which is probed and announced like ones given at startup
+    pub async fn register(&self, service: Service) -> Result<()> {
+        service.validate()?;
+
+        self.send(Command::Register(service)).await
+    }
+
+    /// Changes port and TXT of a registered service with the same name, and announces them again
+    pub async fn update(&self, service: Service) -> Result<()> {
+        service.validate()?;
+
+        self.send(Command::Update(service)).await
+    }
+
+    /// Removes a registered service with the same name, sending goodbye packets for its records
+    pub async fn unregister(&self, service: &Service) -> Result<()> {
+        self.send(Command::Unregister(service.clone())).await
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.commands.send(command).await.map_err(|_| Error::Stopped)
+    }
+
+    /// Flushes pending responses, sends goodbye packets, leaves multicast groups and closes the socket.
+    /// Returns the error the server has stopped with, if any.
+    pub async fn shutdown(self) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+
+        if self.commands.send(Command::Shutdown(reply)).await.is_err() {
+            return Ok(());
+        }
+
+        result.await.unwrap_or(Ok(()))
+    }
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    services: Vec<Service>,
+    hostname: Option<String>,
+    filter: InterfaceFilter,
+    ttl: Ttl,
+    name_callback: Option<NameCallback>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn service(mut self, service: Service) -> Self {
+        self.services.push(service);
+
+        self
+    }
+
+    pub fn services(mut self, services: Vec<Service>) -> Self {
+        self.services.extend(services);
+
+        self
+    }
+
+    /// Host name to advertise instead of the one of OS, with or without `.local` suffix
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_owned());
+
+        self
+    }
+
+    /// TTLs of advertised records, unless overridden by a service
+    pub fn ttl(mut self, ttl: Ttl) -> Self {
+        self.ttl = ttl;
+
+        self
+    }
+
+    /// Restricts listening and answering to matching interfaces. Can be given multiple times, all interfaces are used by default.
+    pub fn allow_interface(mut self, selector: InterfaceSelector) -> Self {
+        self.filter.allow.push(selector);
+
+        self
+    }
+
+    /// Excludes matching interfaces, even if allowed otherwise. A network matching any address of an interface excludes all of its addresses.
+    pub fn deny_interface(mut self, selector: InterfaceSelector) -> Self {
+        self.filter.deny.push(selector);
+
+        self
+    }
+
+    /// Called with the final host and service names after probing, which may differ from requested ones on conflict
+    pub fn on_name<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&NameEvent) + Send + Sync + 'static,
+    {
+        self.name_callback = Some(Arc::new(callback));
+
+        self
+    }
+
+    pub fn build(self) -> Result<Server> {
+        let label = match self.hostname {
+            Some(hostname) => {
+                let label = hostname.strip_suffix(".local").unwrap_or(&hostname);
+                if !is_valid_label(label) {
+                    return Err(Error::InvalidHostname(hostname));
+                }
+
+                label.to_owned()
+            }
+            None => os_hostname()?,
+        };
+        let hostname = format!("{}.local", label);
+        debug!("hostname: {}", hostname);
+
+        for service in &self.services {
+            service.validate()?;
+        }
+
+        let interfaces = scan_interfaces(&self.filter)?;
+
+        let usable = responder_interfaces(&interfaces);
+        for interface in &usable {
+            debug!("ip {:?} in {} on interface {}", interface.ip, interface.prefix, interface.index);
+        }
+
+        if usable.is_empty() {
+            return Err(Error::NoInterface);
+        }
+
+        Ok(Server {
+            services: self.services,
+            hostname,
+            interfaces,
+            filter: self.filter,
+            ttl: self.ttl,
+            name_callback: self.name_callback,
+        })
+    }
+}
+
+// letters, digits and hyphens (rfc1123 2.1)
+fn is_valid_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+// first label of OS host name, with characters not allowed in a label replaced
+fn os_hostname() -> Result<String> {
+    let hostname = hostname::get()?;
+
+    Ok(label_from_hostname(&hostname.to_string_lossy()))
+}
+
+// first label of the hostname with invalid characters replaced, or a random one
+fn label_from_hostname(hostname: &str) -> String {
+    let label = hostname
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x } else { '-' })
+        .take(63)
+        .collect::<String>();
+    let label = label.trim_matches('-');
+
+    if !is_valid_label(label) {
+        return format!("host-{:08x}", rand::thread_rng().gen::<u32>());
+    }
+
+    label.to_owned()
+}
+
+fn scan_interfaces(filter: &InterfaceFilter) -> io::Result<Vec<if_addrs::Interface>> {
+    Ok(filter.apply(if_addrs::get_if_addrs()?))
+}
+
+fn responder_interfaces(interfaces: &[if_addrs::Interface]) -> Vec<Interface> {
+    interfaces
+        .iter()
+        .filter_map(|if_addr| {
+            use if_addrs::IfAddr;
+            let prefix = match &if_addr.addr {
+                IfAddr::V4(addr) => {
+                    if addr.netmask == Ipv4Addr::new(0, 0, 0, 0) || addr.netmask == Ipv4Addr::new(255, 255, 255, 255) {
+                        return None;
+                    }
+                    IpCidr::V4(Ipv4Cidr::from_prefix_and_mask(addr.ip, addr.netmask).ok()?)
+                }
+                IfAddr::V6(addr) => IpCidr::V6(Ipv6Cidr::from_prefix_and_mask(addr.ip, addr.netmask).ok()?),
+            };
+
+            Some(Interface {
+                index: if_addr.index? as InterfaceType,
+                ip: if_addr.ip(),
+                prefix,
+                loopback: if_addr.is_loopback(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hostname_label() {
+        assert!(is_valid_label("my-host1"));
+        assert!(!is_valid_label(""));
+        assert!(!is_valid_label("-host"));
+        assert!(!is_valid_label("host.example"));
+        assert!(!is_valid_label("host_1"));
+        assert!(!is_valid_label(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn os_hostname_label() {
+        assert_eq!(label_from_hostname("my_laptop.example.com"), "my-laptop");
+        assert_eq!(label_from_hostname("-host-"), "host");
+
+        for hostname in ["", "___", "компьютер"] {
+            let label = label_from_hostname(hostname);
+            assert!(label.starts_with("host-") && is_valid_label(&label), "{}", label);
+        }
+    }
+
+    #[test]
+    fn configured_hostname() {
+        let builder = Server::builder;
+
+        assert_eq!(builder().hostname("printer").build().unwrap().hostname, "printer.local");
+        assert_eq!(builder().hostname("printer.local").build().unwrap().hostname, "printer.local");
+        assert!(matches!(builder().hostname("my printer").build(), Err(Error::InvalidHostname(x)) if x == "my printer"));
+    }
+
+    #[test]
+    fn service_too_long() {
+        let service = Service::new("_http._tcp", "web", 80, vec![&"x".repeat(256)]);
+        let result = Server::builder().hostname("printer").service(service).build();
+
+        assert!(matches!(result, Err(Error::InvalidService(x)) if x == "web._http._tcp.local"));
+    }
+}
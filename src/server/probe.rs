@@ -0,0 +1,66 @@
+use std::{cmp::Ordering, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::packet::ResourceRecord;
+
+pub(super) const PROBE_COUNT: usize = 3;
+pub(super) const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+pub(super) const PROBE_DEFER: Duration = Duration::from_secs(1); // after losing simultaneous probe tiebreak
+pub(super) const ANNOUNCE_COUNT: usize = 2;
+pub(super) const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum State {
+    Probing { count: usize, next: Instant },
+    Announcing { count: usize, next: Instant },
+    Established,
+}
+
+impl State {
+    pub fn probing(next: Instant) -> Self {
+        Self::Probing { count: 0, next }
+    }
+
+    pub fn announcing(next: Instant) -> Self {
+        Self::Announcing { count: 0, next }
+    }
+
+    pub fn is_probing(&self) -> bool {
+        matches!(self, Self::Probing { .. })
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match self {
+            Self::Probing { next, .. } | Self::Announcing { next, .. } => Some(*next),
+            Self::Established => None,
+        }
+    }
+}
+
+// rfc6762 8.2
+fn compare_record(lhs: &ResourceRecord, rhs: &ResourceRecord) -> Ordering {
+    u16::from(lhs.class())
+        .cmp(&u16::from(rhs.class()))
+        .then_with(|| u16::from(lhs.r#type()).cmp(&u16::from(rhs.r#type())))
+        .then_with(|| lhs.data().to_bytes().cmp(&rhs.data().to_bytes()))
+}
+
+// compares our proposed records with records in the authority section of other host's probe.
+// Greater means we win the tiebreak.
+pub(super) fn tiebreak(ours: &[&ResourceRecord], theirs: &[&ResourceRecord]) -> Ordering {
+    let mut ours = ours.to_vec();
+    let mut theirs = theirs.to_vec();
+
+    ours.sort_by(|x, y| compare_record(x, y));
+    theirs.sort_by(|x, y| compare_record(x, y));
+
+    for (x, y) in ours.iter().zip(theirs.iter()) {
+        let result = compare_record(x, y);
+        if result != Ordering::Equal {
+            return result;
+        }
+    }
+
+    ours.len().cmp(&theirs.len())
+}
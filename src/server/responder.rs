@@ -0,0 +1,1815 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use cidr_utils::cidr::IpCidr;
+use log::debug;
+use rand::Rng;
+use tokio::time::Instant;
+
+use super::{
+    probe::{self, State, ANNOUNCE_COUNT, ANNOUNCE_INTERVAL, PROBE_COUNT, PROBE_DEFER, PROBE_INTERVAL},
+    NameCallback, NameEvent, MULTICAST_ADDR_V4, MULTICAST_ADDR_V6,
+};
+use crate::{
+    multicast::{InterfaceType, Message},
+    packet::{Name, Packet, Question, ResourceRecord, ResourceRecordData, ResourceType, MAX_LABEL_LENGTH},
+    Service, Ttl,
+};
+
+const SERVICE_TYPE_ENUMERATION_NAME: &str = "_services._dns-sd._udp.local";
+const MULTICAST_INTERVAL: Duration = Duration::from_secs(1);
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+#[derive(Clone)]
+pub(super) struct Interface {
+    pub index: InterfaceType,
+    pub ip: IpAddr,
+    pub prefix: IpCidr,
+    pub loopback: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Destination {
+    Multicast(SocketAddr, InterfaceType),
+    Unicast(SocketAddr, InterfaceType),
+}
+
+pub(super) struct Outgoing {
+    pub packet: Packet,
+    pub destination: Destination,
+}
+
+struct TruncatedQuery {
+    packet: Packet,
+    interface: InterfaceType,
+    deadline: Instant,
+}
+
+// record last multicast to a group on an interface
+struct Transmission {
+    destination: Destination,
+    record: ResourceRecord,
+    time: Instant,
+}
+
+// multicast response with shared or rate limited records, aggregating answers until deadline
+struct DelayedResponse {
+    outgoing: Outgoing,
+    deadline: Instant,
+}
+
+struct Host {
+    requested: String,
+    base: String,
+    name: String,
+    attempt: u32,
+    state: State,
+}
+
+struct Registration {
+    requested: Service,
+    service: Service,
+    attempt: u32,
+    state: State,
+}
+
+// owner of a set of unique records
+#[derive(Clone, Copy)]
+enum Owner {
+    Host,
+    Service(usize),
+}
+
+pub(super) struct Responder {
+    host: Host,
+    services: Vec<Registration>,
+    interfaces: Vec<Interface>,
+    ttl: Ttl,
+    truncated_queries: HashMap<SocketAddr, TruncatedQuery>,
+    delayed_responses: Vec<DelayedResponse>,
+    // rate limited records, kept apart so that shared answers are not held back with them
+    deferred_responses: Vec<DelayedResponse>,
+    transmissions: Vec<Transmission>,
+    name_callback: Option<NameCallback>,
+}
+
+impl Responder {
+    pub fn new(
+        hostname: String,
+        services: Vec<Service>,
+        interfaces: Vec<Interface>,
+        ttl: Ttl,
+        name_callback: Option<NameCallback>,
+        now: Instant,
+    ) -> Self {
+        let base = hostname.strip_suffix(".local").unwrap_or(&hostname).to_owned();
+
+        Self {
+            host: Host {
+                requested: hostname.clone(),
+                base,
+                name: hostname,
+                attempt: 1,
+                state: State::probing(Self::probe_start(now)),
+            },
+            services: services
+                .into_iter()
+                .map(|service| Registration {
+                    requested: service.clone(),
+                    service,
+                    attempt: 1,
+                    state: State::probing(Self::probe_start(now)),
+                })
+                .collect(),
+            interfaces,
+            ttl,
+            truncated_queries: HashMap::new(),
+            delayed_responses: Vec::new(),
+            deferred_responses: Vec::new(),
+            transmissions: Vec::new(),
+            name_callback,
+        }
+    }
+
+    // random delay before first probe (rfc6762 8.1)
+    fn probe_start(now: Instant) -> Instant {
+        now + Duration::from_millis(rand::thread_rng().gen_range(0..250))
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let truncated_queries = self.truncated_queries.values().map(|x| x.deadline);
+        let delayed_responses = self.delayed_responses.iter().chain(&self.deferred_responses).map(|x| x.deadline);
+        let probes = self.owners().into_iter().filter_map(|x| self.state(x).deadline());
+
+        truncated_queries.chain(delayed_responses).chain(probes).min()
+    }
+
+    pub fn handle_timer(&mut self, now: Instant) -> Vec<Outgoing> {
+        let mut responses = Vec::new();
+
+        let expired = self
+            .truncated_queries
+            .iter()
+            .filter(|(_, x)| x.deadline <= now)
+            .map(|(sender, _)| *sender)
+            .collect::<Vec<_>>();
+
+        for sender in expired {
+            responses.extend(self.respond_truncated(&sender));
+        }
+
+        for delayed in [&mut self.delayed_responses, &mut self.deferred_responses] {
+            let (expired, pending) = delayed.drain(..).partition(|x| x.deadline <= now);
+            *delayed = pending;
+            responses.extend(expired.into_iter().map(|x: DelayedResponse| x.outgoing));
+        }
+
+        let mut result = self.rate_limit(responses, now);
+
+        let mut probing = Vec::new();
+        let mut announcing = Vec::new();
+        for owner in self.owners() {
+            if let State::Probing { count, next } = *self.state(owner) {
+                if next > now {
+                    continue;
+                }
+
+                if count < PROBE_COUNT {
+                    *self.state_mut(owner) = State::Probing {
+                        count: count + 1,
+                        next: now + PROBE_INTERVAL,
+                    };
+                    probing.push((owner, count == 0));
+                } else {
+                    *self.state_mut(owner) = State::announcing(now);
+                    self.established(owner);
+                }
+            }
+
+            if let State::Announcing { count, next } = *self.state(owner) {
+                if next > now {
+                    continue;
+                }
+
+                *self.state_mut(owner) = if count + 1 < ANNOUNCE_COUNT {
+                    State::Announcing {
+                        count: count + 1,
+                        next: now + ANNOUNCE_INTERVAL,
+                    }
+                } else {
+                    State::Established
+                };
+                announcing.push(owner);
+            }
+        }
+
+        if !probing.is_empty() {
+            result.extend(self.create_probes(&probing));
+        }
+
+        if !announcing.is_empty() {
+            let announcements = self.create_announcements(&announcing, false);
+            self.record_transmissions(&announcements, now);
+
+            result.extend(announcements);
+        }
+
+        result
+    }
+
+    // defers records multicast on the same interface within last second until a second has passed (rfc6762 6)
+    fn rate_limit(&mut self, responses: Vec<Outgoing>, now: Instant) -> Vec<Outgoing> {
+        self.transmissions.retain(|x| x.time + MULTICAST_INTERVAL > now);
+
+        let mut result = Vec::new();
+        for mut response in responses {
+            if matches!(response.destination, Destination::Multicast(..)) {
+                let last_sent = |record: &ResourceRecord| {
+                    self.transmissions
+                        .iter()
+                        .find(|x| x.destination == response.destination && &x.record == record)
+                        .map(|x| x.time)
+                };
+
+                let (deferred, answers): (Vec<_>, Vec<_>) = response.packet.answers.drain(..).partition(|x| last_sent(x).is_some());
+                let ready = deferred.iter().filter_map(last_sent).max();
+                response.packet.answers = answers;
+                response.packet.additionals.retain(|x| last_sent(x).is_none());
+
+                if let Some(ready) = ready {
+                    self.defer(response.destination, deferred, ready + MULTICAST_INTERVAL);
+                }
+            }
+
+            if !response.packet.answers.is_empty() {
+                result.push(response);
+            }
+        }
+
+        self.record_transmissions(&result, now);
+
+        result
+    }
+
+    fn defer(&mut self, destination: Destination, answers: Vec<ResourceRecord>, deadline: Instant) {
+        let packet = Packet::new_response(0, Vec::new(), answers, Vec::new(), Vec::new());
+
+        match self
+            .deferred_responses
+            .iter_mut()
+            .find(|x| x.outgoing.destination == destination && x.deadline >= deadline)
+        {
+            Some(deferred) => Self::aggregate(&mut deferred.outgoing.packet, packet),
+            None => self.deferred_responses.push(DelayedResponse {
+                outgoing: Outgoing { packet, destination },
+                deadline,
+            }),
+        }
+    }
+
+    // also removes sent records from responses still pending to the same destination
+    fn record_transmissions(&mut self, outgoing: &[Outgoing], now: Instant) {
+        for outgoing in outgoing.iter().filter(|x| matches!(x.destination, Destination::Multicast(..))) {
+            for record in outgoing.packet.answers.iter().chain(&outgoing.packet.additionals) {
+                self.transmissions
+                    .retain(|x| x.destination != outgoing.destination || &x.record != record);
+                self.transmissions.push(Transmission {
+                    destination: outgoing.destination,
+                    record: record.clone(),
+                    time: now,
+                });
+            }
+
+            for delayed in self
+                .delayed_responses
+                .iter_mut()
+                .chain(&mut self.deferred_responses)
+                .filter(|x| x.outgoing.destination == outgoing.destination)
+            {
+                delayed.outgoing.packet.answers.retain(|x| !outgoing.packet.answers.contains(x));
+            }
+        }
+
+        self.delayed_responses.retain(|x| !x.outgoing.packet.answers.is_empty());
+        self.deferred_responses.retain(|x| !x.outgoing.packet.answers.is_empty());
+    }
+
+    // answer all pending truncated queries without waiting for their continuation, and send delayed responses
+    pub fn flush(&mut self) -> Vec<Outgoing> {
+        let pending = self.truncated_queries.keys().copied().collect::<Vec<_>>();
+
+        let mut result = pending.into_iter().flat_map(|sender| self.respond_truncated(&sender)).collect::<Vec<_>>();
+        result.extend(
+            self.delayed_responses
+                .drain(..)
+                .chain(self.deferred_responses.drain(..))
+                .map(|x| x.outgoing),
+        );
+
+        result
+    }
+
+    fn respond_truncated(&mut self, sender: &SocketAddr) -> Vec<Outgoing> {
+        let query = self.truncated_queries.remove(sender).unwrap();
+
+        self.respond(&query.packet, sender, query.interface)
+    }
+
+    // goodbye packets for all established records (rfc6762 10.1)
+    pub fn goodbye(&self) -> Vec<Outgoing> {
+        let owners = self.owners().into_iter().filter(|x| !self.state(*x).is_probing()).collect::<Vec<_>>();
+
+        self.create_announcements(&owners, true)
+    }
+
+    pub fn register(&mut self, service: Service, now: Instant) {
+        if self.find_registration(&service.name).is_some() {
+            debug!("Service {} is already registered", service.name);
+            return;
+        }
+
+        self.services.push(Registration {
+            requested: service.clone(),
+            service,
+            attempt: 1,
+            state: State::probing(Self::probe_start(now)),
+        });
+    }
+
+    // changed SRV and TXT data are announced again (rfc6762 8.4)
+    pub fn update(&mut self, service: Service, now: Instant) {
+        let Some(index) = self.find_registration(&service.name) else {
+            debug!("Can't update unknown service {}", service.name);
+            return;
+        };
+
+        let registration = &mut self.services[index];
+        registration.service = Service {
+            port: service.port,
+            txt: service.txt.clone(),
+            ttl: service.ttl,
+            ..registration.service.clone()
+        };
+        registration.requested = service;
+
+        if !registration.state.is_probing() {
+            registration.state = State::announcing(now);
+        }
+    }
+
+    // goodbye for records of the service, except ones shared with remaining services
+    pub fn unregister(&mut self, service: &Service) -> Vec<Outgoing> {
+        let Some(index) = self.find_registration(&service.name) else {
+            debug!("Can't unregister unknown service {}", service.name);
+            return Vec::new();
+        };
+
+        let registration = self.services.remove(index);
+        if registration.state.is_probing() {
+            return Vec::new();
+        }
+
+        self.create_unsolicited(
+            |index| {
+                let remaining = self.records(&self.interface_ips(index));
+
+                self.registration_records(&registration.service)
+                    .into_iter()
+                    .filter(|x| !remaining.contains(x))
+                    .collect()
+            },
+            true,
+        )
+    }
+
+    // goodbye for addresses removed from remaining interfaces, and announcements for new ones (rfc6762 8.3, 10.1)
+    pub fn set_interfaces(&mut self, interfaces: Vec<Interface>, now: Instant) -> Vec<Outgoing> {
+        let same = |x: &Interface, y: &Interface| x.index == y.index && x.ip == y.ip;
+
+        let removed = self
+            .interfaces
+            .iter()
+            .filter(|x| !interfaces.iter().any(|y| same(x, y)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let added = interfaces
+            .iter()
+            .filter(|x| !self.interfaces.iter().any(|y| same(x, y)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let new_link = added.iter().any(|x| !self.interfaces.iter().any(|y| y.index == x.index));
+
+        self.interfaces = interfaces;
+
+        for interface in &removed {
+            debug!("Removed {} from interface {}", interface.ip, interface.index);
+        }
+        for interface in &added {
+            debug!("Added {} to interface {}", interface.ip, interface.index);
+        }
+
+        // everything is new to hosts on a new link, only addresses on others
+        let owners = if new_link { self.owners() } else { vec![Owner::Host] };
+        if !added.is_empty() {
+            for owner in owners {
+                if !self.state(owner).is_probing() {
+                    *self.state_mut(owner) = State::announcing(now);
+                }
+            }
+        }
+
+        if removed.is_empty() || self.host.state.is_probing() {
+            return Vec::new();
+        }
+
+        self.create_unsolicited(
+            |index| {
+                let ips = removed.iter().filter(|x| x.index == index).map(|x| x.ip).collect::<Vec<_>>();
+
+                self.host_records(&ips)
+            },
+            true,
+        )
+    }
+
+    fn find_registration(&self, requested: &str) -> Option<usize> {
+        self.services.iter().position(|x| x.requested.name.eq_ignore_ascii_case(requested))
+    }
+
+    pub fn handle_message(&mut self, message: &Message, now: Instant) -> Vec<Outgoing> {
+        // socket receives on all interfaces, including excluded ones
+        if !self.interfaces.iter().any(|x| x.index == message.interface) {
+            return Vec::new();
+        }
+
+        let packet = match Packet::parse(&message.data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("Invalid packet from {}: {}", message.sender, err);
+
+                return Vec::new();
+            }
+        };
+
+        if packet.header.is_response() {
+            self.handle_response(&packet, message, now);
+
+            return Vec::new();
+        }
+
+        // rfc6762 18.3, 18.11
+        if packet.header.opcode() != 0 || packet.header.rcode() != 0 {
+            debug!(
+                "Ignoring query from {} with opcode {} rcode {}",
+                message.sender,
+                packet.header.opcode(),
+                packet.header.rcode()
+            );
+
+            return Vec::new();
+        }
+
+        if !packet.nameservers.is_empty() {
+            self.handle_probe(&packet, message, now);
+        }
+
+        // a new query ends a truncated one of the same sender, which is answered with known answers received so far
+        let pending = if !packet.questions.is_empty() && self.truncated_queries.contains_key(&message.sender) {
+            self.respond_truncated(&message.sender)
+        } else {
+            Vec::new()
+        };
+        let mut result = self.rate_limit(pending, now);
+
+        if let Some(query) = self.receive_query(packet, message, now) {
+            let responses = self.respond(&query, &message.sender, message.interface);
+            let responses = self.delay_shared(responses, now);
+
+            // probes are defended regardless of rate limit
+            if query.nameservers.is_empty() {
+                result.extend(self.rate_limit(responses, now));
+            } else {
+                self.record_transmissions(&responses, now);
+                result.extend(responses);
+            }
+        }
+
+        result
+    }
+
+    // multicast responses with shared records are delayed and aggregated, others are sent immediately (rfc6762 6)
+    fn delay_shared(&mut self, responses: Vec<Outgoing>, now: Instant) -> Vec<Outgoing> {
+        let mut result = Vec::new();
+
+        for response in responses {
+            // only unique records have cache flush bit set
+            let shared = response.packet.answers.iter().any(|x| !x.cache_flush());
+            if !matches!(response.destination, Destination::Multicast(..)) || !shared {
+                result.push(response);
+                continue;
+            }
+
+            match self.delayed_responses.iter_mut().find(|x| x.outgoing.destination == response.destination) {
+                Some(delayed) => Self::aggregate(&mut delayed.outgoing.packet, response.packet),
+                None => self.delayed_responses.push(DelayedResponse {
+                    outgoing: response,
+                    deadline: now + Duration::from_millis(rand::thread_rng().gen_range(20..=120)),
+                }),
+            }
+        }
+
+        result
+    }
+
+    fn aggregate(packet: &mut Packet, other: Packet) {
+        for record in other.answers {
+            if !packet.answers.contains(&record) {
+                packet.answers.push(record);
+            }
+        }
+
+        for record in other.additionals {
+            if !packet.answers.contains(&record) && !packet.additionals.contains(&record) {
+                packet.additionals.push(record);
+            }
+        }
+        packet.additionals.retain(|x| !packet.answers.contains(x));
+    }
+
+    // returns query to be answered now, if any
+    fn receive_query(&mut self, packet: Packet, message: &Message, now: Instant) -> Option<Packet> {
+        // known answers of truncated query are continued in following packets without questions (rfc6762 7.2)
+        if packet.questions.is_empty() {
+            let mut query = self.truncated_queries.remove(&message.sender)?;
+            query.packet.answers.extend(packet.answers);
+
+            if packet.header.is_truncated() {
+                query.deadline = Self::truncated_query_deadline(now);
+                self.truncated_queries.insert(message.sender, query);
+
+                return None;
+            }
+
+            return Some(query.packet);
+        }
+
+        if packet.header.is_truncated() {
+            self.truncated_queries.insert(
+                message.sender,
+                TruncatedQuery {
+                    packet,
+                    interface: message.interface,
+                    deadline: Self::truncated_query_deadline(now),
+                },
+            );
+
+            return None;
+        }
+
+        Some(packet)
+    }
+
+    fn truncated_query_deadline(now: Instant) -> Instant {
+        now + Duration::from_millis(rand::thread_rng().gen_range(400..=500))
+    }
+
+    // conflicting responses (rfc6762 8.1, 9)
+    fn handle_response(&mut self, packet: &Packet, message: &Message, now: Instant) {
+        if self.is_own_packet(message) {
+            return;
+        }
+
+        for record in packet.answers.iter().chain(&packet.additionals) {
+            for owner in self.owners() {
+                if !record.name().equals(self.owner_name(owner)) {
+                    continue;
+                }
+
+                match self.state(owner) {
+                    State::Probing { .. } => {
+                        debug!("Conflicting record {} while probing, renaming", record.name());
+
+                        self.rename(owner, now);
+                    }
+                    State::Announcing { .. } | State::Established => {
+                        if self.is_conflicting(owner, record) {
+                            debug!("Conflicting record {}, probing again", record.name());
+
+                            *self.state_mut(owner) = State::probing(now);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // simultaneous probe tiebreaking (rfc6762 8.2)
+    fn handle_probe(&mut self, packet: &Packet, message: &Message, now: Instant) {
+        if self.is_own_packet(message) {
+            return;
+        }
+
+        let ips = self.interface_ips(message.interface);
+
+        for owner in self.owners() {
+            if !matches!(self.state(owner), State::Probing { .. }) {
+                continue;
+            }
+
+            let name = self.owner_name(owner);
+            if !packet.questions.iter().any(|x| x.name.equals(name)) {
+                continue;
+            }
+
+            let theirs = packet.nameservers.iter().filter(|x| x.name().equals(name)).collect::<Vec<_>>();
+            if theirs.is_empty() {
+                continue;
+            }
+
+            let ours = self.unique_records(owner, &ips);
+            if probe::tiebreak(&ours.iter().collect::<Vec<_>>(), &theirs) == Ordering::Less {
+                debug!("Lost simultaneous probe tiebreak for {}, deferring", name);
+
+                *self.state_mut(owner) = State::Probing {
+                    count: 0,
+                    next: now + PROBE_DEFER,
+                };
+            }
+        }
+    }
+
+    fn respond(&self, query: &Packet, sender: &SocketAddr, interface: InterfaceType) -> Vec<Outgoing> {
+        let (unicast_response, multicast_response) = self.handle_query(query, sender, interface);
+
+        let unicast_response = unicast_response.map(|packet| Outgoing {
+            packet,
+            destination: Destination::Unicast(*sender, interface),
+        });
+        let multicast_response = multicast_response.map(|packet| Outgoing {
+            packet,
+            destination: Destination::Multicast(Self::multicast_addr(sender.ip()), interface),
+        });
+
+        unicast_response.into_iter().chain(multicast_response).collect()
+    }
+
+    fn handle_query(&self, packet: &Packet, sender: &SocketAddr, interface: InterfaceType) -> (Option<Packet>, Option<Packet>) {
+        let ips = match self.find_local_ips(&sender.ip(), interface) {
+            Some(x) => x,
+            None => {
+                debug!("Can't find local ip address for {}", sender.ip());
+
+                return (None, None);
+            }
+        };
+        let records = self
+            .records(&ips)
+            .into_iter()
+            .filter(|x| !Self::is_known_answer(x, &packet.answers))
+            .collect::<Vec<_>>();
+
+        // legacy resolvers don't send from mdns port and expect a conventional reply (rfc6762 6.7)
+        let legacy = sender.port() != MULTICAST_ADDR_V4.port();
+
+        let mut unicast_answers = Vec::new();
+        let mut multicast_answers = Vec::new();
+
+        for question in &packet.questions {
+            let answers = if question.unicast || legacy {
+                &mut unicast_answers
+            } else {
+                &mut multicast_answers
+            };
+
+            for record in records.iter().filter(|x| Self::answers_question(x, question)) {
+                if !answers.contains(record) {
+                    answers.push(record.clone());
+                }
+            }
+        }
+
+        let unicast_response = Self::create_response(packet.header.id(), unicast_answers, &records);
+        if legacy {
+            return (unicast_response.map(|x| Self::legacy_response(packet, x)), None);
+        }
+
+        let multicast_response = Self::create_response(packet.header.id(), multicast_answers, &records);
+
+        (unicast_response, multicast_response)
+    }
+
+    // echoes questions, with ttl capped and without cache flush bit (rfc6762 6.7, 10.2)
+    fn legacy_response(query: &Packet, response: Packet) -> Packet {
+        let legacy = |records: Vec<ResourceRecord>| {
+            records
+                .into_iter()
+                .map(|x| {
+                    let ttl = x.ttl().min(LEGACY_UNICAST_TTL);
+
+                    x.with_ttl(ttl).with_cache_flush(false)
+                })
+                .collect()
+        };
+
+        Packet::new_response(
+            query.header.id(),
+            query.questions.clone(),
+            legacy(response.answers),
+            Vec::new(),
+            legacy(response.additionals),
+        )
+    }
+
+    // querier already has the record with at least half of its ttl remaining (rfc6762 7.1)
+    fn is_known_answer(record: &ResourceRecord, known_answers: &[ResourceRecord]) -> bool {
+        known_answers
+            .iter()
+            .any(|x| x.name() == record.name() && x.data() == record.data() && x.ttl() >= record.ttl() / 2)
+    }
+
+    fn answers_question(record: &ResourceRecord, question: &Question) -> bool {
+        record.name() == &question.name && (question.r#type == ResourceType::ANY || question.r#type == record.r#type())
+    }
+
+    fn create_response(id: u16, answers: Vec<ResourceRecord>, records: &[ResourceRecord]) -> Option<Packet> {
+        if answers.is_empty() {
+            return None;
+        }
+
+        let additionals = Self::additional_records(&answers, records);
+
+        Some(Packet::new_response(id, Vec::new(), answers, Vec::new(), additionals))
+    }
+
+    // rfc6763 12
+    fn additional_records(answers: &[ResourceRecord], records: &[ResourceRecord]) -> Vec<ResourceRecord> {
+        let mut additionals: Vec<ResourceRecord> = Vec::new();
+        let mut queue = answers.iter().collect::<VecDeque<_>>();
+
+        while let Some(record) = queue.pop_front() {
+            let related = records.iter().filter(|x| match record.data() {
+                ResourceRecordData::PTR(name) => x.name() == name && matches!(x.r#type(), ResourceType::SRV | ResourceType::TXT),
+                ResourceRecordData::SRV { target, .. } => x.name() == target && matches!(x.r#type(), ResourceType::A | ResourceType::AAAA),
+                ResourceRecordData::A(_) | ResourceRecordData::AAAA(_) => {
+                    x.name() == record.name() && matches!(x.r#type(), ResourceType::A | ResourceType::AAAA)
+                }
+                _ => false,
+            });
+
+            for x in related {
+                if !answers.contains(x) && !additionals.contains(x) {
+                    additionals.push(x.clone());
+                    queue.push_back(x);
+                }
+            }
+        }
+
+        additionals
+    }
+
+    fn create_probes(&self, owners: &[(Owner, bool)]) -> Vec<Outgoing> {
+        self.multicast_interfaces()
+            .into_iter()
+            .map(|(group, index)| {
+                let ips = self.interface_ips(index);
+
+                // QU bit on first probe (rfc6762 8.1)
+                let questions = owners
+                    .iter()
+                    .map(|(owner, first)| Question::new(self.owner_name(*owner), ResourceType::ANY, *first))
+                    .collect();
+                // cache flush bit is only meaningful in responses (rfc6762 10.2)
+                let authorities = owners
+                    .iter()
+                    .flat_map(|(owner, _)| self.unique_records(*owner, &ips))
+                    .map(|x| x.with_cache_flush(false))
+                    .collect();
+
+                Outgoing {
+                    packet: Packet::new_query(0, questions, Vec::new(), authorities),
+                    destination: Destination::Multicast(group, index),
+                }
+            })
+            .collect()
+    }
+
+    // unsolicited responses with all records of owners (rfc6762 8.3)
+    fn create_announcements(&self, owners: &[Owner], goodbye: bool) -> Vec<Outgoing> {
+        self.create_unsolicited(
+            |index| {
+                let ips = self.interface_ips(index);
+
+                owners.iter().flat_map(|x| self.owner_records(*x, &ips)).collect()
+            },
+            goodbye,
+        )
+    }
+
+    // unsolicited responses with records for each interface, with zero TTL for goodbye
+    fn create_unsolicited<F>(&self, records: F, goodbye: bool) -> Vec<Outgoing>
+    where
+        F: Fn(InterfaceType) -> Vec<ResourceRecord>,
+    {
+        self.multicast_interfaces()
+            .into_iter()
+            .filter_map(|(group, index)| {
+                let mut answers = Vec::new();
+                for record in records(index) {
+                    let record = if goodbye { record.with_ttl(0) } else { record };
+                    if !answers.contains(&record) {
+                        answers.push(record);
+                    }
+                }
+
+                (!answers.is_empty()).then(|| Outgoing {
+                    packet: Packet::new_response(0, Vec::new(), answers, Vec::new(), Vec::new()),
+                    destination: Destination::Multicast(group, index),
+                })
+            })
+            .collect()
+    }
+
+    // all established records, with given addresses for the host
+    fn records(&self, ips: &[IpAddr]) -> Vec<ResourceRecord> {
+        let mut records = Vec::new();
+
+        for owner in self.owners().into_iter().filter(|x| !self.state(*x).is_probing()) {
+            for record in self.owner_records(owner, ips) {
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            }
+        }
+
+        records
+    }
+
+    // shared and unique records of an owner
+    fn owner_records(&self, owner: Owner, ips: &[IpAddr]) -> Vec<ResourceRecord> {
+        match owner {
+            Owner::Host => self.host_records(ips),
+            Owner::Service(i) => self.registration_records(&self.services[i].service),
+        }
+    }
+
+    // PTR, SRV, TXT and service type enumeration records of a service
+    fn registration_records(&self, service: &Service) -> Vec<ResourceRecord> {
+        // PTR record
+        let mut records = vec![ResourceRecord::new(
+            &service.r#type,
+            service.ttl.unwrap_or(self.ttl).ptr,
+            ResourceRecordData::PTR(Name::new(&service.name)),
+        )];
+
+        // SRV, TXT record
+        records.extend(self.service_records(service));
+
+        // service type enumeration (rfc6763 9)
+        // shared by services of a type, so server-wide TTL is used
+        records.push(ResourceRecord::new(
+            SERVICE_TYPE_ENUMERATION_NAME,
+            self.ttl.ptr,
+            ResourceRecordData::PTR(Name::new(&service.r#type)),
+        ));
+
+        records
+    }
+
+    fn service_records(&self, service: &Service) -> Vec<ResourceRecord> {
+        let ttl = service.ttl.unwrap_or(self.ttl);
+
+        let mut records = vec![ResourceRecord::new(
+            &service.name,
+            ttl.srv,
+            ResourceRecordData::SRV {
+                priority: 0,
+                weight: 0,
+                port: service.port,
+                target: Name::new(&self.host.name),
+            },
+        )
+        .with_cache_flush(true)];
+
+        if !service.txt.is_empty() {
+            records.push(ResourceRecord::new(&service.name, ttl.txt, ResourceRecordData::TXT(service.txt.clone())).with_cache_flush(true));
+        }
+
+        records
+    }
+
+    fn host_records(&self, ips: &[IpAddr]) -> Vec<ResourceRecord> {
+        ips.iter()
+            .map(|ip| {
+                let data = match ip {
+                    IpAddr::V4(ip) => ResourceRecordData::A(*ip),
+                    IpAddr::V6(ip) => ResourceRecordData::AAAA(*ip),
+                };
+
+                ResourceRecord::new(&self.host.name, self.ttl.address, data).with_cache_flush(true)
+            })
+            .collect()
+    }
+
+    fn owners(&self) -> Vec<Owner> {
+        let services = (0..self.services.len()).map(Owner::Service);
+
+        [Owner::Host].into_iter().chain(services).collect()
+    }
+
+    fn owner_name(&self, owner: Owner) -> &str {
+        match owner {
+            Owner::Host => &self.host.name,
+            Owner::Service(i) => &self.services[i].service.name,
+        }
+    }
+
+    fn state(&self, owner: Owner) -> &State {
+        match owner {
+            Owner::Host => &self.host.state,
+            Owner::Service(i) => &self.services[i].state,
+        }
+    }
+
+    fn state_mut(&mut self, owner: Owner) -> &mut State {
+        match owner {
+            Owner::Host => &mut self.host.state,
+            Owner::Service(i) => &mut self.services[i].state,
+        }
+    }
+
+    fn unique_records(&self, owner: Owner, ips: &[IpAddr]) -> Vec<ResourceRecord> {
+        match owner {
+            Owner::Host => self.host_records(ips),
+            Owner::Service(i) => self.service_records(&self.services[i].service),
+        }
+    }
+
+    fn is_conflicting(&self, owner: Owner, record: &ResourceRecord) -> bool {
+        match (owner, record.data()) {
+            (Owner::Host, ResourceRecordData::A(ip)) => !self.interfaces.iter().any(|x| x.ip == *ip),
+            (Owner::Host, ResourceRecordData::AAAA(ip)) => !self.interfaces.iter().any(|x| x.ip == *ip),
+            (Owner::Service(i), ResourceRecordData::SRV { .. } | ResourceRecordData::TXT(_)) => {
+                !self.service_records(&self.services[i].service).iter().any(|x| x.data() == record.data())
+            }
+            _ => false,
+        }
+    }
+
+    fn rename(&mut self, owner: Owner, now: Instant) {
+        match owner {
+            Owner::Host => {
+                self.host.attempt += 1;
+
+                // the base label is cut so that the suffixed one stays within 63 octets
+                let suffix = format!("-{}", self.host.attempt);
+                let base = &self.host.base[..self.host.base.len().min(MAX_LABEL_LENGTH - suffix.len())];
+                self.host.name = format!("{}{}.local", base.trim_end_matches('-'), suffix);
+                self.host.state = State::probing(now);
+            }
+            Owner::Service(i) => {
+                let registration = &mut self.services[i];
+
+                registration.attempt += 1;
+                registration.service = registration.requested.renamed(registration.attempt);
+                registration.state = State::probing(now);
+            }
+        }
+
+        debug!("Renamed to {}", self.owner_name(owner));
+    }
+
+    fn established(&self, owner: Owner) {
+        let event = match owner {
+            Owner::Host => NameEvent::Hostname {
+                requested: self.host.requested.clone(),
+                name: self.host.name.clone(),
+            },
+            Owner::Service(i) => NameEvent::Service {
+                requested: self.services[i].requested.name.clone(),
+                name: self.services[i].service.name.clone(),
+            },
+        };
+        debug!("Established {:?}", event);
+
+        if let Some(callback) = &self.name_callback {
+            callback(&event);
+        }
+    }
+
+    fn is_own_packet(&self, message: &Message) -> bool {
+        self.interfaces.iter().any(|x| x.ip == message.sender.ip())
+    }
+
+    // multicast group and interface pairs, for each address family present on interface
+    fn multicast_interfaces(&self) -> Vec<(SocketAddr, InterfaceType)> {
+        let mut result = Vec::new();
+        for interface in self.interfaces.iter().filter(|x| !x.loopback) {
+            let item = (Self::multicast_addr(interface.ip), interface.index);
+            if !result.contains(&item) {
+                result.push(item);
+            }
+        }
+
+        result
+    }
+
+    fn multicast_addr(ip: IpAddr) -> SocketAddr {
+        match ip {
+            IpAddr::V4(_) => MULTICAST_ADDR_V4,
+            IpAddr::V6(_) => MULTICAST_ADDR_V6,
+        }
+    }
+
+    fn interface_ips(&self, index: InterfaceType) -> Vec<IpAddr> {
+        self.interfaces.iter().filter(|x| x.index == index).map(|x| x.ip).collect()
+    }
+
+    // addresses of the interface the query was received on. ipv4 addresses are limited to the sender's subnet,
+    // ipv6 ones are all included as link-local and global addresses coexist.
+    fn find_local_ips(&self, remote_addr: &IpAddr, index: InterfaceType) -> Option<Vec<IpAddr>> {
+        let interfaces = self.interfaces.iter().filter(|x| x.index == index).collect::<Vec<_>>();
+
+        let interface = interfaces.iter().find(|x| x.prefix.contains(*remote_addr))?;
+        debug!("remote_addr: {:?}, interface ip: {:?} in {}", remote_addr, interface.ip, interface.prefix);
+
+        let ips = interfaces
+            .iter()
+            .filter(|x| remote_addr.is_ipv6() || x.ip.is_ipv6() || x.prefix.contains(*remote_addr))
+            .map(|x| x.ip)
+            .collect();
+
+        Some(ips)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddrV4},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::packet::HeaderFlags;
+
+    fn interface(ip: IpAddr, prefix: &str) -> Interface {
+        Interface {
+            index: 2,
+            ip,
+            prefix: IpCidr::from_str(prefix).unwrap(),
+            loopback: false,
+        }
+    }
+
+    fn responder() -> Responder {
+        Responder::new(
+            "hostname.local".into(),
+            vec![
+                Service::new("_raop._tcp", "test", 1234, vec!["testtest"]),
+                Service::new("_airplay._tcp", "test", 1235, vec![]),
+                Service::new("_raop._tcp", "test2", 1236, vec![]),
+            ],
+            vec![interface(Ipv4Addr::new(192, 168, 1, 1).into(), "192.168.1.0/24")],
+            Ttl::default(),
+            None,
+            Instant::now(),
+        )
+    }
+
+    // without random delay before first probe
+    fn probing(now: Instant) -> Responder {
+        let mut responder = responder();
+
+        responder.host.state = State::probing(now);
+        for registration in &mut responder.services {
+            registration.state = State::probing(now);
+        }
+
+        responder
+    }
+
+    fn established() -> Responder {
+        let mut responder = responder();
+
+        responder.host.state = State::Established;
+        for registration in &mut responder.services {
+            registration.state = State::Established;
+        }
+
+        responder
+    }
+
+    fn message(packet: &Packet, sender: Ipv4Addr) -> Message {
+        Message {
+            data: packet.write(),
+            sender: SocketAddrV4::new(sender, 5353).into(),
+            interface: 2,
+        }
+    }
+
+    fn query(name: &str, r#type: ResourceType) -> (Option<Packet>, Option<Packet>) {
+        let query = Packet::new_query(0, vec![Question::new(name, r#type, false)], Vec::new(), Vec::new());
+
+        established().handle_query(&query, &SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 5353).into(), 2)
+    }
+
+    fn types(records: &[ResourceRecord]) -> Vec<ResourceType> {
+        records.iter().map(|x| x.r#type()).collect()
+    }
+
+    #[test]
+    fn answer_ptr() {
+        let (unicast, multicast) = query("_raop._tcp.local", ResourceType::PTR);
+        let multicast = multicast.unwrap();
+
+        assert!(unicast.is_none());
+        assert_eq!(types(&multicast.answers), vec![ResourceType::PTR, ResourceType::PTR]);
+        assert_eq!(
+            types(&multicast.additionals),
+            vec![ResourceType::SRV, ResourceType::TXT, ResourceType::SRV, ResourceType::A]
+        );
+    }
+
+    #[test]
+    fn answer_srv_and_txt() {
+        let multicast = query("test._raop._tcp.local", ResourceType::SRV).1.unwrap();
+        assert_eq!(types(&multicast.answers), vec![ResourceType::SRV]);
+        assert_eq!(types(&multicast.additionals), vec![ResourceType::A]);
+
+        let multicast = query("test._raop._tcp.local", ResourceType::TXT).1.unwrap();
+        assert_eq!(types(&multicast.answers), vec![ResourceType::TXT]);
+        assert!(multicast.additionals.is_empty());
+    }
+
+    #[test]
+    fn answer_a() {
+        let multicast = query("HOSTNAME.local", ResourceType::A).1.unwrap();
+
+        assert_eq!(multicast.answers.len(), 1);
+        assert!(matches!(multicast.answers[0].data(), ResourceRecordData::A(x) if *x == Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(multicast.additionals.is_empty());
+    }
+
+    #[test]
+    fn answer_any() {
+        let multicast = query("test._raop._tcp.local", ResourceType::ANY).1.unwrap();
+
+        assert_eq!(types(&multicast.answers), vec![ResourceType::SRV, ResourceType::TXT]);
+        assert_eq!(types(&multicast.additionals), vec![ResourceType::A]);
+    }
+
+    #[test]
+    fn record_ttls() {
+        let ttls = |responder: &Responder, name: &str| {
+            let query = Packet::new_query(0, vec![Question::new(name, ResourceType::ANY, false)], Vec::new(), Vec::new());
+            let multicast = responder
+                .handle_query(&query, &SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 5353).into(), 2)
+                .1
+                .unwrap();
+
+            multicast.answers.iter().map(|x| (x.r#type(), x.ttl())).collect::<Vec<_>>()
+        };
+
+        let mut responder = established();
+        assert_eq!(ttls(&responder, "hostname.local"), vec![(ResourceType::A, 120)]);
+        assert_eq!(
+            ttls(&responder, "test._raop._tcp.local"),
+            vec![(ResourceType::SRV, 120), (ResourceType::TXT, 4500)]
+        );
+
+        let ttl = Ttl {
+            address: 10,
+            srv: 20,
+            ptr: 30,
+            txt: 40,
+        };
+        responder.services[0].service = responder.services[0].service.clone().with_ttl(ttl);
+        assert_eq!(
+            ttls(&responder, "test._raop._tcp.local"),
+            vec![(ResourceType::SRV, 20), (ResourceType::TXT, 40)]
+        );
+        assert_eq!(
+            ttls(&responder, "_raop._tcp.local"),
+            vec![(ResourceType::PTR, 30), (ResourceType::PTR, 4500)]
+        );
+        assert_eq!(ttls(&responder, "hostname.local"), vec![(ResourceType::A, 120)]);
+    }
+
+    #[test]
+    fn answer_service_type_enumeration() {
+        let multicast = query("_services._dns-sd._udp.local", ResourceType::PTR).1.unwrap();
+
+        let types = multicast
+            .answers
+            .iter()
+            .map(|x| match x.data() {
+                ResourceRecordData::PTR(name) => name.to_string(),
+                _ => panic!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["_raop._tcp.local", "_airplay._tcp.local"]);
+        assert!(multicast.additionals.is_empty());
+    }
+
+    #[test]
+    fn ignore_unknown() {
+        assert!(matches!(query("other.local", ResourceType::A), (None, None)));
+        assert!(matches!(query("hostname.local", ResourceType::AAAA), (None, None)));
+    }
+
+    #[test]
+    fn probe_and_establish() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let now = Instant::now();
+
+        let mut responder = responder();
+        responder.name_callback = Some({
+            let events = events.clone();
+            Arc::new(move |x: &NameEvent| events.lock().unwrap().push(x.clone()))
+        });
+
+        // no answer while probing
+        assert!(responder.records(&[Ipv4Addr::new(192, 168, 1, 1).into()]).is_empty());
+
+        for i in 0..PROBE_COUNT {
+            let probes = responder.handle_timer(now + PROBE_INTERVAL * (i as u32 + 1));
+
+            assert_eq!(probes.len(), 1);
+            let probe = &probes[0].packet;
+            assert_eq!(probe.questions.len(), 4);
+            assert!(probe.questions.iter().all(|x| x.r#type == ResourceType::ANY && x.unicast == (i == 0)));
+            assert!(probe.nameservers.iter().all(|x| !x.cache_flush()));
+            assert_eq!(
+                types(&probe.nameservers),
+                vec![
+                    ResourceType::A,
+                    ResourceType::SRV,
+                    ResourceType::TXT,
+                    ResourceType::SRV,
+                    ResourceType::SRV
+                ]
+            );
+        }
+
+        // established, first announcement is sent right away
+        let announcements = responder.handle_timer(now + PROBE_INTERVAL * 4);
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(events.lock().unwrap().len(), 4);
+        assert_eq!(
+            events.lock().unwrap()[1],
+            NameEvent::Service {
+                requested: "test._raop._tcp.local".into(),
+                name: "test._raop._tcp.local".into()
+            }
+        );
+    }
+
+    #[test]
+    fn announce_after_probing() {
+        let now = Instant::now();
+        let mut responder = probing(now);
+
+        for i in 0..PROBE_COUNT {
+            responder.handle_timer(now + PROBE_INTERVAL * (i as u32 + 1));
+        }
+
+        let established = now + PROBE_INTERVAL * (PROBE_COUNT as u32 + 1);
+        for i in 0..ANNOUNCE_COUNT {
+            let announcements = responder.handle_timer(established + ANNOUNCE_INTERVAL * i as u32);
+
+            assert_eq!(announcements.len(), 1);
+            assert_eq!(announcements[0].destination, Destination::Multicast(MULTICAST_ADDR_V4, 2));
+
+            let packet = &announcements[0].packet;
+            assert!(packet.header.is_response() && packet.header.is_authoritative());
+            assert!(packet.questions.is_empty());
+            assert_eq!(
+                types(&packet.answers),
+                vec![
+                    ResourceType::A,
+                    ResourceType::PTR,
+                    ResourceType::SRV,
+                    ResourceType::TXT,
+                    ResourceType::PTR,
+                    ResourceType::PTR,
+                    ResourceType::SRV,
+                    ResourceType::PTR,
+                    ResourceType::PTR,
+                    ResourceType::SRV,
+                ]
+            );
+            // shared PTR records must not flush other responders' records from caches
+            assert!(packet.answers.iter().all(|x| x.cache_flush() == (x.r#type() != ResourceType::PTR)));
+        }
+
+        assert_eq!(responder.host.state, State::Established);
+        assert!(responder.next_deadline().is_none());
+    }
+
+    #[test]
+    fn flush_truncated_query() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        let mut query = Packet::new_query(
+            0,
+            vec![Question::new("_raop._tcp.local", ResourceType::PTR, false)],
+            Vec::new(),
+            Vec::new(),
+        );
+        query.header = query.header.with_flags(HeaderFlags::TRUNCATED, true);
+
+        assert!(responder.handle_message(&message(&query, Ipv4Addr::new(192, 168, 1, 10)), now).is_empty());
+
+        let flushed = responder.flush();
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].packet.answers.len(), 2);
+        assert!(responder.next_deadline().is_none());
+    }
+
+    #[test]
+    fn known_answer_suppression() {
+        let known_answer = |ttl| ResourceRecord::new("_raop._tcp.local", ttl, ResourceRecordData::PTR(Name::new("test._raop._tcp.local")));
+        let sender = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 5353).into();
+        let question = Question::new("_raop._tcp.local", ResourceType::PTR, false);
+
+        let query = Packet::new_query(0, vec![question.clone()], vec![known_answer(2250)], Vec::new());
+        let multicast = established().handle_query(&query, &sender, 2).1.unwrap();
+        assert_eq!(multicast.answers.len(), 1);
+        assert!(matches!(multicast.answers[0].data(), ResourceRecordData::PTR(x) if x.equals("test2._raop._tcp.local")));
+
+        // less than half of ttl remaining
+        let query = Packet::new_query(0, vec![question], vec![known_answer(2249)], Vec::new());
+        let multicast = established().handle_query(&query, &sender, 2).1.unwrap();
+        assert_eq!(multicast.answers.len(), 2);
+    }
+
+    #[test]
+    fn known_answer_suppression_truncated() {
+        let now = Instant::now();
+        let mut responder = established();
+        let sender = Ipv4Addr::new(192, 168, 1, 10);
+
+        let mut query = Packet::new_query(
+            0,
+            vec![Question::new("_raop._tcp.local", ResourceType::PTR, false)],
+            Vec::new(),
+            Vec::new(),
+        );
+        query.header = query.header.with_flags(HeaderFlags::TRUNCATED, true);
+        assert!(responder.handle_message(&message(&query, sender), now).is_empty());
+
+        // known answers continued in a packet without questions
+        let known_answers = ["test", "test2"]
+            .iter()
+            .map(|x| {
+                ResourceRecord::new(
+                    "_raop._tcp.local",
+                    4500,
+                    ResourceRecordData::PTR(Name::new(&format!("{}._raop._tcp.local", x))),
+                )
+            })
+            .collect();
+        let continuation = Packet::new_query(0, Vec::new(), known_answers, Vec::new());
+
+        assert!(responder.handle_message(&message(&continuation, sender), now).is_empty());
+        assert!(responder.next_deadline().is_none());
+    }
+
+    #[test]
+    fn consecutive_truncated_queries() {
+        let now = Instant::now();
+        let mut responder = established();
+        let sender = Ipv4Addr::new(192, 168, 1, 10);
+        let truncated_query = |name| {
+            let mut query = Packet::new_query(0, vec![Question::new(name, ResourceType::PTR, false)], Vec::new(), Vec::new());
+            query.header = query.header.with_flags(HeaderFlags::TRUNCATED, true);
+
+            query
+        };
+
+        assert!(responder
+            .handle_message(&message(&truncated_query("_raop._tcp.local"), sender), now)
+            .is_empty());
+
+        // first query is answered when the second one starts, instead of being replaced by it
+        let responses = responder.handle_message(&message(&truncated_query("_airplay._tcp.local"), sender), now);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].packet.answers.iter().all(|x| x.name().equals("_raop._tcp.local")));
+        assert_eq!(responses[0].packet.answers.len(), 2);
+
+        let deadline = responder.next_deadline().unwrap();
+        let responses = responder.handle_timer(deadline);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].packet.answers.iter().all(|x| x.name().equals("_airplay._tcp.local")));
+        assert!(responder.next_deadline().is_none());
+    }
+
+    #[test]
+    fn delay_shared_answers() {
+        let now = Instant::now();
+        let mut responder = established();
+        let ptr_query = |name| Packet::new_query(0, vec![Question::new(name, ResourceType::PTR, false)], Vec::new(), Vec::new());
+
+        assert!(responder
+            .handle_message(&message(&ptr_query("_raop._tcp.local"), Ipv4Addr::new(192, 168, 1, 10)), now)
+            .is_empty());
+        let deadline = responder.next_deadline().unwrap();
+        assert!(deadline >= now + Duration::from_millis(20) && deadline <= now + Duration::from_millis(120));
+
+        // answers to queries within the delay are aggregated
+        assert!(responder
+            .handle_message(&message(&ptr_query("_airplay._tcp.local"), Ipv4Addr::new(192, 168, 1, 11)), now)
+            .is_empty());
+        assert_eq!(responder.next_deadline(), Some(deadline));
+
+        let responses = responder.handle_timer(deadline);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].destination, Destination::Multicast(MULTICAST_ADDR_V4, 2));
+        assert_eq!(types(&responses[0].packet.answers), vec![ResourceType::PTR; 3]);
+        assert!(responder.next_deadline().is_none());
+
+        // unique records are answered immediately, once rate limit of the additionals above has passed
+        let query = Packet::new_query(
+            0,
+            vec![Question::new("test._raop._tcp.local", ResourceType::SRV, false)],
+            Vec::new(),
+            Vec::new(),
+        );
+        let message = message(&query, Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(responder.handle_message(&message, deadline + MULTICAST_INTERVAL).len(), 1);
+    }
+
+    #[test]
+    fn rate_limit_multicast() {
+        let now = Instant::now();
+        let mut responder = established();
+        let query = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::A, false)], Vec::new(), Vec::new());
+        let message = message(&query, Ipv4Addr::new(192, 168, 1, 10));
+
+        assert_eq!(responder.handle_message(&message, now).len(), 1);
+        assert!(responder.handle_message(&message, now + Duration::from_millis(500)).is_empty());
+        assert_eq!(responder.handle_message(&message, now + MULTICAST_INTERVAL).len(), 1);
+
+        // unicast responses are not limited
+        let query = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::A, true)], Vec::new(), Vec::new());
+        assert_eq!(
+            responder
+                .handle_message(&self::message(&query, Ipv4Addr::new(192, 168, 1, 10)), now)
+                .len(),
+            1
+        );
+        assert_eq!(
+            responder
+                .handle_message(&self::message(&query, Ipv4Addr::new(192, 168, 1, 10)), now)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn defer_rate_limited() {
+        let now = Instant::now();
+        let mut responder = established();
+        let query = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::A, false)], Vec::new(), Vec::new());
+        let message = message(&query, Ipv4Addr::new(192, 168, 1, 10));
+
+        assert_eq!(responder.handle_message(&message, now).len(), 1);
+        assert!(responder.handle_message(&message, now + Duration::from_millis(300)).is_empty());
+        assert!(responder.handle_message(&message, now + Duration::from_millis(600)).is_empty());
+        assert_eq!(responder.next_deadline(), Some(now + MULTICAST_INTERVAL));
+
+        // both queries are answered once, when a second has passed
+        assert!(responder.handle_timer(now + MULTICAST_INTERVAL - Duration::from_millis(1)).is_empty());
+        let responses = responder.handle_timer(now + MULTICAST_INTERVAL);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].destination, Destination::Multicast(MULTICAST_ADDR_V4, 2));
+        assert_eq!(types(&responses[0].packet.answers), vec![ResourceType::A]);
+        assert!(responder.next_deadline().is_none());
+
+        // answering again in the meantime sends the record once
+        assert!(responder.handle_message(&message, now + Duration::from_millis(1500)).is_empty());
+        assert_eq!(responder.handle_message(&message, now + Duration::from_millis(2000)).len(), 1);
+        assert!(responder.next_deadline().is_none());
+    }
+
+    #[test]
+    fn shared_answers_not_held_by_deferred() {
+        let now = Instant::now();
+        let mut responder = established();
+        let a_query = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::A, false)], Vec::new(), Vec::new());
+        let ptr_query = Packet::new_query(
+            0,
+            vec![Question::new("_raop._tcp.local", ResourceType::PTR, false)],
+            Vec::new(),
+            Vec::new(),
+        );
+        let sender = Ipv4Addr::new(192, 168, 1, 10);
+
+        assert_eq!(responder.handle_message(&message(&a_query, sender), now).len(), 1);
+        assert!(responder
+            .handle_message(&message(&a_query, sender), now + Duration::from_millis(200))
+            .is_empty());
+
+        let query_time = now + Duration::from_millis(300);
+        assert!(responder.handle_message(&message(&ptr_query, sender), query_time).is_empty());
+        let deadline = responder.next_deadline().unwrap();
+        assert!(deadline >= query_time + Duration::from_millis(20) && deadline <= query_time + Duration::from_millis(120));
+
+        let responses = responder.handle_timer(deadline);
+        assert_eq!(responses.len(), 1);
+        assert!(types(&responses[0].packet.answers).iter().all(|x| *x == ResourceType::PTR));
+
+        // the rate limited record still goes out after its second
+        assert_eq!(responder.next_deadline(), Some(now + MULTICAST_INTERVAL));
+        let responses = responder.handle_timer(now + MULTICAST_INTERVAL);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(types(&responses[0].packet.answers), vec![ResourceType::A]);
+    }
+
+    #[test]
+    fn legacy_unicast() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        let question = Question::new("test._raop._tcp.local", ResourceType::SRV, false);
+        let query = Packet::new_query(1234, vec![question.clone()], Vec::new(), Vec::new());
+        let mut message = message(&query, Ipv4Addr::new(192, 168, 1, 10));
+        message.sender.set_port(40000);
+
+        let responses = responder.handle_message(&message, now);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].destination, Destination::Unicast(message.sender, 2));
+
+        let packet = &responses[0].packet;
+        assert_eq!(packet.header.id(), 1234);
+        assert_eq!(packet.questions, vec![question]);
+        assert_eq!(types(&packet.answers), vec![ResourceType::SRV]);
+        assert_eq!(types(&packet.additionals), vec![ResourceType::A]);
+        assert!(packet
+            .answers
+            .iter()
+            .chain(&packet.additionals)
+            .all(|x| x.ttl() == 10 && !x.cache_flush()));
+    }
+
+    #[test]
+    fn register_service() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        responder.register(Service::new("_http._tcp", "web", 80, vec![]), now);
+        assert!(responder.services[3].state.is_probing());
+        assert_eq!(responder.next_deadline(), Some(responder.services[3].state.deadline().unwrap()));
+
+        // registering same name again is ignored
+        responder.register(Service::new("_http._tcp", "web", 8080, vec![]), now);
+        assert_eq!(responder.services.len(), 4);
+    }
+
+    #[test]
+    fn update_service() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        responder.update(Service::new("_raop._tcp", "test", 4321, vec!["new"]), now);
+
+        let announcements = responder.handle_timer(now);
+        assert_eq!(announcements.len(), 1);
+
+        let answers = &announcements[0].packet.answers;
+        assert!(answers.iter().any(|x| matches!(x.data(), ResourceRecordData::SRV { port: 4321, .. })));
+        assert!(
+            answers.contains(&ResourceRecord::new("test._raop._tcp.local", 4500, ResourceRecordData::TXT(vec!["new".into()])).with_cache_flush(true))
+        );
+    }
+
+    #[test]
+    fn unregister_service() {
+        let mut responder = established();
+
+        let goodbye = responder.unregister(&Service::new("_raop._tcp", "test", 1234, vec![]));
+        assert_eq!(responder.services.len(), 2);
+
+        // type enumeration is still needed for test2._raop._tcp.local
+        assert_eq!(goodbye.len(), 1);
+        assert_eq!(
+            types(&goodbye[0].packet.answers),
+            vec![ResourceType::PTR, ResourceType::SRV, ResourceType::TXT]
+        );
+        assert!(goodbye[0].packet.answers.iter().all(|x| x.ttl() == 0));
+
+        let goodbye = responder.unregister(&Service::new("_airplay._tcp", "test", 1235, vec![]));
+        assert_eq!(
+            types(&goodbye[0].packet.answers),
+            vec![ResourceType::PTR, ResourceType::SRV, ResourceType::PTR]
+        );
+
+        assert!(responder.unregister(&Service::new("_airplay._tcp", "test", 1235, vec![])).is_empty());
+    }
+
+    fn dual_stack() -> Responder {
+        let mut responder = established();
+        responder.interfaces.extend([
+            interface(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).into(), "fe80::/64"),
+            interface(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), "2001:db8::/64"),
+        ]);
+
+        responder
+    }
+
+    #[test]
+    fn answer_aaaa() {
+        let responder = dual_stack();
+        let query = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::ANY, false)], Vec::new(), Vec::new());
+
+        let sender = SocketAddr::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x10).into(), 5353);
+        let responses = responder.respond(&query, &sender, 2);
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].destination, Destination::Multicast(MULTICAST_ADDR_V6, 2));
+        assert_eq!(
+            types(&responses[0].packet.answers),
+            vec![ResourceType::A, ResourceType::AAAA, ResourceType::AAAA]
+        );
+
+        // ipv4 sender gets addresses of both families too
+        let sender = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 10).into(), 5353);
+        let responses = responder.respond(&query, &sender, 2);
+
+        assert_eq!(responses[0].destination, Destination::Multicast(MULTICAST_ADDR_V4, 2));
+        assert_eq!(responses[0].packet.answers.len(), 3);
+
+        // not on the link
+        let sender = SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1).into(), 5353);
+        assert!(responder.respond(&query, &sender, 2).is_empty());
+    }
+
+    #[test]
+    fn announce_on_both_families() {
+        let goodbye = dual_stack().goodbye();
+
+        assert_eq!(goodbye.len(), 2);
+        assert_eq!(goodbye[0].destination, Destination::Multicast(MULTICAST_ADDR_V4, 2));
+        assert_eq!(goodbye[1].destination, Destination::Multicast(MULTICAST_ADDR_V6, 2));
+        assert_eq!(goodbye[0].packet, goodbye[1].packet);
+    }
+
+    #[test]
+    fn interface_changes() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        // new address on known interface is announced for host only
+        let mut interfaces = responder.interfaces.clone();
+        interfaces.push(interface(Ipv4Addr::new(10, 0, 0, 1).into(), "10.0.0.0/8"));
+        assert!(responder.set_interfaces(interfaces.clone(), now).is_empty());
+        assert!(matches!(responder.host.state, State::Announcing { count: 0, .. }));
+        assert_eq!(responder.services[0].state, State::Established);
+
+        let announcements = responder.handle_timer(now);
+        assert_eq!(types(&announcements[0].packet.answers), vec![ResourceType::A, ResourceType::A]);
+
+        // goodbye for removed address
+        interfaces.remove(0);
+        let goodbye = responder.set_interfaces(interfaces.clone(), now);
+        assert_eq!(goodbye.len(), 1);
+        assert_eq!(goodbye[0].packet.answers.len(), 1);
+        assert_eq!(goodbye[0].packet.answers[0].ttl(), 0);
+        assert_eq!(*goodbye[0].packet.answers[0].data(), ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 1)));
+
+        // everything is announced on a new link
+        interfaces.push(Interface {
+            index: 3,
+            ..interface(Ipv4Addr::new(172, 16, 0, 1).into(), "172.16.0.0/12")
+        });
+        responder.set_interfaces(interfaces, now);
+        assert!(responder.owners().iter().all(|x| matches!(responder.state(*x), State::Announcing { .. })));
+    }
+
+    #[test]
+    fn ignore_excluded_interface() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        let query = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::A, false)], Vec::new(), Vec::new());
+        let mut message = message(&query, Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(responder.handle_message(&message, now).len(), 1);
+
+        message.interface = 3;
+        assert!(responder.handle_message(&message, now).is_empty());
+    }
+
+    #[test]
+    fn goodbye() {
+        // nothing to say goodbye to while probing
+        assert!(responder().goodbye().is_empty());
+
+        let goodbye = established().goodbye();
+
+        assert_eq!(goodbye.len(), 1);
+        assert_eq!(goodbye[0].destination, Destination::Multicast(MULTICAST_ADDR_V4, 2));
+        assert_eq!(goodbye[0].packet.answers.len(), 10);
+        assert!(goodbye[0].packet.answers.iter().all(|x| x.ttl() == 0));
+    }
+
+    #[test]
+    fn rename_on_conflict() {
+        let now = Instant::now();
+        let mut responder = probing(now);
+
+        responder.handle_timer(now + PROBE_INTERVAL);
+
+        let srv = ResourceRecord::new(
+            "test._raop._tcp.local",
+            120,
+            ResourceRecordData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 1,
+                target: Name::new("other.local"),
+            },
+        );
+        let a = ResourceRecord::new("hostname.local", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 2)));
+        let response = Packet::new_response(0, Vec::new(), vec![srv, a], Vec::new(), Vec::new());
+
+        responder.handle_message(&message(&response, Ipv4Addr::new(192, 168, 1, 2)), now + PROBE_INTERVAL);
+
+        assert_eq!(responder.host.name, "hostname-2.local");
+        assert_eq!(responder.services[0].service.name, "test (2)._raop._tcp.local");
+        assert_eq!(responder.services[0].service.instance, "test (2)");
+        assert_eq!(responder.services[1].service.name, "test._airplay._tcp.local");
+        assert!(matches!(responder.services[0].state, State::Probing { count: 0, .. }));
+        assert!(matches!(responder.services[1].state, State::Probing { count: 1, .. }));
+
+        // own packets are not conflicts
+        let a = ResourceRecord::new("hostname-2.local", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 3)));
+        let response = Packet::new_response(0, Vec::new(), vec![a], Vec::new(), Vec::new());
+        responder.handle_message(&message(&response, Ipv4Addr::new(192, 168, 1, 1)), now + PROBE_INTERVAL);
+
+        assert_eq!(responder.host.name, "hostname-2.local");
+    }
+
+    #[test]
+    fn rename_long_hostname() {
+        let now = Instant::now();
+        let mut responder = responder();
+        responder.host.base = "h".repeat(MAX_LABEL_LENGTH);
+
+        responder.rename(Owner::Host, now);
+
+        assert_eq!(responder.host.name, format!("{}-2.local", "h".repeat(MAX_LABEL_LENGTH - 2)));
+    }
+
+    #[test]
+    fn conflict_after_established() {
+        let now = Instant::now();
+        let mut responder = established();
+
+        // same data is not a conflict
+        let a = ResourceRecord::new("hostname.local", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 1)));
+        let response = Packet::new_response(0, Vec::new(), vec![a], Vec::new(), Vec::new());
+        responder.handle_message(&message(&response, Ipv4Addr::new(192, 168, 1, 2)), now);
+        assert_eq!(responder.host.state, State::Established);
+
+        let a = ResourceRecord::new("hostname.local", 120, ResourceRecordData::A(Ipv4Addr::new(192, 168, 1, 2)));
+        let response = Packet::new_response(0, Vec::new(), vec![a], Vec::new(), Vec::new());
+        responder.handle_message(&message(&response, Ipv4Addr::new(192, 168, 1, 2)), now);
+
+        assert_eq!(responder.host.name, "hostname.local");
+        assert!(matches!(responder.host.state, State::Probing { count: 0, .. }));
+    }
+
+    #[test]
+    fn simultaneous_probe_tiebreak() {
+        let now = Instant::now();
+        let mut responder = probing(now);
+
+        responder.handle_timer(now + PROBE_INTERVAL);
+
+        let probe = |ip| {
+            let a = ResourceRecord::new("hostname.local", 120, ResourceRecordData::A(ip));
+            let probe = Packet::new_query(0, vec![Question::new("hostname.local", ResourceType::ANY, true)], Vec::new(), vec![a]);
+
+            message(&probe, Ipv4Addr::new(192, 168, 1, 5))
+        };
+
+        // lexicographically earlier data loses
+        responder.handle_message(&probe(Ipv4Addr::new(192, 168, 1, 0)), now + PROBE_INTERVAL);
+        assert!(matches!(responder.host.state, State::Probing { count: 1, .. }));
+
+        responder.handle_message(&probe(Ipv4Addr::new(192, 168, 1, 5)), now + PROBE_INTERVAL);
+        assert_eq!(
+            responder.host.state,
+            State::Probing {
+                count: 0,
+                next: now + PROBE_INTERVAL + PROBE_DEFER
+            }
+        );
+        assert_eq!(responder.host.name, "hostname.local");
+    }
+}
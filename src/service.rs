@@ -1,24 +1,96 @@
 use log::debug;
 
+use crate::{
+    error::{Error, Result},
+    packet::{Name, MAX_STRING_LENGTH},
+    Ttl,
+};
+
+#[derive(Clone)]
 pub struct Service {
     pub(super) r#type: String,
+    pub(super) instance: String,
     pub(super) name: String,
     pub(super) port: u16,
     pub(super) txt: Vec<String>,
+    pub(super) ttl: Option<Ttl>,
 }
 
 impl Service {
     pub fn new(r#type: &str, name: &str, port: u16, txt: Vec<&str>) -> Self {
         let r#type = format!("{}.local", r#type);
+        let instance = name.to_owned();
         let name = format!("{}.{}", name, r#type);
 
         debug!("New service {} {}", r#type, name);
 
         Self {
             r#type,
+            instance,
             name,
             port,
             txt: txt.into_iter().map(|x| x.into()).collect::<Vec<_>>(),
+            ttl: None,
+        }
+    }
+
+    /// Overrides server-wide TTLs for records of this service, which are its instance PTR, SRV and TXT records.
+    /// `address` is ignored, as A and AAAA records belong to the host and always use the server-wide TTL.
+    pub fn with_ttl(self, ttl: Ttl) -> Self {
+        Self { ttl: Some(ttl), ..self }
+    }
+
+    // names and TXT strings must fit their length octets when written
+    pub(super) fn validate(&self) -> Result<()> {
+        if !Name::is_valid(&self.name) || self.txt.iter().any(|x| x.len() > MAX_STRING_LENGTH) {
+            return Err(Error::InvalidService(self.name.clone()));
+        }
+
+        Ok(())
+    }
+
+    // "name (2)", "name (3)", ... as suggested by rfc6762 9, dropping characters at the end of the name if it would get too long
+    pub(super) fn renamed(&self, attempt: u32) -> Self {
+        let suffix = format!(" ({})", attempt);
+        let mut base = self.instance.clone();
+
+        loop {
+            let instance = format!("{}{}", base, suffix);
+            let name = format!("{}.{}", instance, self.r#type);
+
+            if Name::is_valid(&name) || base.is_empty() {
+                return Self {
+                    instance,
+                    name,
+                    ..self.clone()
+                };
+            }
+
+            base.pop();
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        assert!(Service::new("_http._tcp", "web", 80, vec!["path=/"]).validate().is_ok());
+        assert!(Service::new("_http._tcp", &"w".repeat(64), 80, vec![]).validate().is_err());
+        assert!(Service::new("_http._tcp", "web", 80, vec![&"x".repeat(256)]).validate().is_err());
+    }
+
+    #[test]
+    fn renamed_within_label_limit() {
+        let service = Service::new("_http._tcp", "web", 80, vec![]).renamed(2);
+        assert_eq!(service.instance, "web (2)");
+        assert_eq!(service.name, "web (2)._http._tcp.local");
+
+        let instance = format!("{}é", "w".repeat(61));
+        let service = Service::new("_http._tcp", &instance, 80, vec![]).renamed(12);
+        assert_eq!(service.instance, format!("{} (12)", "w".repeat(58)));
+        assert!(service.validate().is_ok());
+    }
+}
@@ -0,0 +1,24 @@
+/// Time to live of advertised records in seconds, per kind of record.
+/// Defaults are the ones recommended by rfc6762 10.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ttl {
+    /// A and AAAA records of the host
+    pub address: u32,
+    /// SRV records, which contain the host name
+    pub srv: u32,
+    /// PTR records of service instances and service types
+    pub ptr: u32,
+    /// TXT records of service instances
+    pub txt: u32,
+}
+
+impl Default for Ttl {
+    fn default() -> Self {
+        Self {
+            address: 120,
+            srv: 120,
+            ptr: 4500,
+            txt: 4500,
+        }
+    }
+}
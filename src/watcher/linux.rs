@@ -0,0 +1,110 @@
+use std::{
+    io,
+    mem::size_of,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use nix::sys::socket::{bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+use tokio::io::unix::AsyncFd;
+
+// rtnetlink socket subscribed to link and address notifications
+pub struct InterfaceWatcher {
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl InterfaceWatcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        )?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let groups = libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR;
+        bind(fd.as_raw_fd(), &NetlinkAddr::new(0, groups as u32))?;
+
+        Ok(Self {
+            socket: unsafe { AsyncFd::register(fd).map_err(io::Error::from)? },
+        })
+    }
+
+    // returns when a link or an address has been added, changed or removed
+    pub async fn changed(&self) -> io::Result<()> {
+        loop {
+            let mut guard = self.socket.readable().await?;
+
+            match guard.try_io(|socket| Self::read_inner(socket.as_raw_fd())) {
+                Ok(Ok(true)) => return Ok(()),
+                Ok(Ok(false)) => continue,
+                Ok(Err(err)) => return Err(err),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    fn read_inner(fd: RawFd) -> io::Result<bool> {
+        let mut buf = vec![0; 8192];
+
+        let read_bytes = match recv(fd, &mut buf, MsgFlags::empty()) {
+            Ok(x) => x,
+            // notifications were dropped, we can't tell what has changed
+            Err(nix::Error::ENOBUFS) => return Ok(true),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self::has_change(&buf[..read_bytes]))
+    }
+
+    fn has_change(mut data: &[u8]) -> bool {
+        let header_size = size_of::<libc::nlmsghdr>();
+
+        while data.len() >= header_size {
+            let length = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+            let r#type = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+
+            if matches!(r#type, libc::RTM_NEWLINK | libc::RTM_DELLINK | libc::RTM_NEWADDR | libc::RTM_DELADDR) {
+                return true;
+            }
+
+            // messages are 4 byte aligned
+            let length = (length + 3) & !3;
+            if length < header_size || length > data.len() {
+                break;
+            }
+            data = &data[length..];
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(r#type: u16, length: u32) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend(length.to_ne_bytes());
+        result.extend(r#type.to_ne_bytes());
+        result.resize(length as usize, 0);
+
+        result
+    }
+
+    #[test]
+    fn detect_change() {
+        assert!(InterfaceWatcher::has_change(&message(libc::RTM_NEWADDR, 16)));
+        assert!(!InterfaceWatcher::has_change(&message(libc::RTM_NEWROUTE, 16)));
+
+        // second message in a batch
+        let mut data = message(libc::RTM_NEWROUTE, 18);
+        data.resize(20, 0);
+        data.extend(message(libc::RTM_DELLINK, 16));
+        assert!(InterfaceWatcher::has_change(&data));
+
+        // truncated
+        assert!(!InterfaceWatcher::has_change(&message(libc::RTM_NEWADDR, 16)[..8]));
+    }
+}
@@ -0,0 +1,14 @@
+use std::io;
+
+// interface changes are not detected on this platform, interfaces found at startup are used
+pub struct InterfaceWatcher;
+
+impl InterfaceWatcher {
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub async fn changed(&self) -> io::Result<()> {
+        std::future::pending().await
+    }
+}